@@ -0,0 +1,96 @@
+// send_to/recv_from と send_batch/recv_batch のスループットを比べる
+// raw ソケットを使うので root (CAP_NET_RAW) で実行する: cargo bench --bench batch
+use std::net::{self, SocketAddr};
+use std::time::{Duration, Instant};
+use udp::{RecvSlot, UdpSocket};
+
+const PAYLOAD_SIZE: usize = 64;
+const BATCH_SIZE: usize = 32;
+const SEND_COUNT: usize = 200_000;
+const RECV_COUNT: usize = 200_000;
+// 受信バッファに収まるように1回に送る数
+const BURST_SIZE: usize = 128;
+
+// 宛先にカーネルのソケットも開いておく (無いと ICMP Port Unreachable が返ってきて送信が失敗する)
+fn sink() -> (net::UdpSocket, SocketAddr) {
+    let sink = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = sink.local_addr().unwrap();
+    (sink, addr)
+}
+
+fn report(name: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{:<12} {:>9} datagrams in {:>7.3}s  {:>10.0} datagrams/s",
+        name,
+        count,
+        elapsed.as_secs_f64(),
+        count as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn bench_send(batch: bool) {
+    let (_sink, dest) = sink();
+    let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let payload = [0u8; PAYLOAD_SIZE];
+    let datagrams = vec![(&payload[..], dest); BATCH_SIZE];
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < SEND_COUNT {
+        if batch {
+            sent += socket.send_batch(&datagrams).unwrap();
+        } else {
+            socket.send_to(&payload, dest).unwrap();
+            sent += 1;
+        }
+    }
+    report(
+        if batch { "send_batch" } else { "send_to" },
+        sent,
+        start.elapsed(),
+    );
+}
+
+// 受信バッファに収まる数ずつ送っておき、受信にかかった時間だけを測る
+fn bench_recv(batch: bool) {
+    let (_sink, addr) = sink();
+    let mut socket = UdpSocket::bind(addr).unwrap();
+    let mut sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let payload = [0u8; PAYLOAD_SIZE];
+    let datagrams = vec![(&payload[..], addr); BURST_SIZE];
+    let mut buffer = [0u8; PAYLOAD_SIZE];
+    let mut slots = (0..BATCH_SIZE)
+        .map(|_| RecvSlot::new(PAYLOAD_SIZE))
+        .collect::<Vec<_>>();
+    let mut elapsed = Duration::ZERO;
+    let mut received = 0;
+    while received < RECV_COUNT {
+        sender.send_batch(&datagrams).unwrap();
+        let start = Instant::now();
+        let mut remaining = BURST_SIZE;
+        while remaining > 0 {
+            remaining -= if batch {
+                socket.recv_batch(&mut slots).unwrap()
+            } else {
+                socket.recv_from(&mut buffer).map(|_| 1).unwrap()
+            };
+        }
+        elapsed += start.elapsed();
+        received += BURST_SIZE;
+    }
+    report(
+        if batch { "recv_batch" } else { "recv_from" },
+        received,
+        elapsed,
+    );
+}
+
+fn main() {
+    if let Err(e) = UdpSocket::bind("127.0.0.1:0") {
+        eprintln!("skipping: {}", e);
+        return;
+    }
+    bench_send(false);
+    bench_send(true);
+    bench_recv(false);
+    bench_recv(true);
+}
@@ -0,0 +1,112 @@
+// ARP による IPv4 アドレスから MAC アドレスへの解決
+use pnet::packet::arp::{
+    ArpHardwareTypes, ArpOperation, ArpOperations, ArpPacket, MutableArpPacket,
+};
+use pnet::packet::ethernet::EtherTypes;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+// Ethernet 上の IPv4 の ARP パケットの長さ
+pub(crate) const PACKET_SIZE: usize = 28;
+// 解決したエントリを使う期間
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+
+// ARP パケットの内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Message {
+    pub(crate) operation: ArpOperation,
+    pub(crate) sender_mac: MacAddr,
+    pub(crate) sender_ip: Ipv4Addr,
+    pub(crate) target_mac: MacAddr,
+    pub(crate) target_ip: Ipv4Addr,
+}
+
+impl Message {
+    // target_ip の MAC アドレスを問い合わせる
+    pub(crate) fn request(sender_mac: MacAddr, sender_ip: Ipv4Addr, target_ip: Ipv4Addr) -> Self {
+        Self {
+            operation: ArpOperations::Request,
+            sender_mac,
+            sender_ip,
+            target_mac: MacAddr::zero(),
+            target_ip,
+        }
+    }
+
+    // 問い合わせに自分の MAC アドレスを答える
+    pub(crate) fn reply(&self, mac: MacAddr) -> Self {
+        Self {
+            operation: ArpOperations::Reply,
+            sender_mac: mac,
+            sender_ip: self.target_ip,
+            target_mac: self.sender_mac,
+            target_ip: self.sender_ip,
+        }
+    }
+
+    // Ethernet/IPv4 以外の ARP パケットは None を返す
+    pub(crate) fn parse(buffer: &[u8]) -> Option<Self> {
+        let packet = ArpPacket::new(buffer)?;
+        if packet.get_hardware_type() != ArpHardwareTypes::Ethernet
+            || packet.get_protocol_type() != EtherTypes::Ipv4
+            || packet.get_hw_addr_len() != 6
+            || packet.get_proto_addr_len() != 4
+        {
+            return None;
+        }
+        Some(Self {
+            operation: packet.get_operation(),
+            sender_mac: packet.get_sender_hw_addr(),
+            sender_ip: packet.get_sender_proto_addr(),
+            target_mac: packet.get_target_hw_addr(),
+            target_ip: packet.get_target_proto_addr(),
+        })
+    }
+
+    pub(crate) fn write(&self, buffer: &mut [u8]) {
+        let mut packet = MutableArpPacket::new(buffer).unwrap();
+        packet.set_hardware_type(ArpHardwareTypes::Ethernet);
+        packet.set_protocol_type(EtherTypes::Ipv4);
+        packet.set_hw_addr_len(6);
+        packet.set_proto_addr_len(4);
+        packet.set_operation(self.operation);
+        packet.set_sender_hw_addr(self.sender_mac);
+        packet.set_sender_proto_addr(self.sender_ip);
+        packet.set_target_hw_addr(self.target_mac);
+        packet.set_target_proto_addr(self.target_ip);
+    }
+}
+
+// 解決済みのアドレス
+pub(crate) struct Cache {
+    entries: HashMap<Ipv4Addr, (MacAddr, Instant)>,
+}
+
+impl Cache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    // 期限切れのエントリは捨てる
+    pub(crate) fn get(&mut self, ip: Ipv4Addr) -> Option<MacAddr> {
+        let (mac, expires_at) = *self.entries.get(&ip)?;
+        if expires_at <= Instant::now() {
+            self.entries.remove(&ip);
+            return None;
+        }
+        Some(mac)
+    }
+
+    pub(crate) fn contains(&self, ip: Ipv4Addr) -> bool {
+        self.entries.contains_key(&ip)
+    }
+
+    pub(crate) fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        self.entries
+            .insert(ip, (mac, Instant::now() + ENTRY_LIFETIME));
+    }
+}
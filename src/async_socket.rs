@@ -0,0 +1,93 @@
+// tokio のランタイム上で使う UdpSocket
+// ソケットのディスクリプタを AsyncFd に登録し、読み込み可能になるまで待つ
+use crate::{Error, RecvMeta, Result, UdpSocket};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+pub struct AsyncUdpSocket {
+    inner: AsyncFd<UdpSocket>,
+}
+
+impl AsyncUdpSocket {
+    // 指定したローカルアドレスにバインドしてランタイムに登録する
+    pub fn bind<T: ToSocketAddrs>(addr: T) -> Result<Self> {
+        Self::new(UdpSocket::bind(addr)?)
+    }
+
+    // 生成済みのソケットをランタイムに登録する。ランタイムの中で呼び出す必要がある
+    // 待つためのディスクリプタを持たないバックエンド (SimNetwork など) には使えない
+    pub fn new(mut socket: UdpSocket) -> Result<Self> {
+        socket.check_pollable()?;
+        socket.set_nonblocking(true);
+        let inner = AsyncFd::with_interest(socket, Interest::READABLE)?;
+        Ok(Self { inner })
+    }
+
+    // 登録を解除して元のブロッキングのソケットに戻す
+    pub fn into_inner(self) -> UdpSocket {
+        let mut socket = self.inner.into_inner();
+        socket.set_nonblocking(false);
+        socket
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        self.inner.get_ref()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.get_ref().local_addr()
+    }
+
+    pub fn connect<T: ToSocketAddrs>(&mut self, addr: T) -> Result<()> {
+        self.inner.get_mut().connect(addr)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    // raw ソケットの送信はバッファに空きがあればすぐに終わるので、書き込み可能になるのは待たない
+    pub async fn send_to<T: ToSocketAddrs>(&mut self, payload: &[u8], dest: T) -> Result<usize> {
+        self.inner.get_mut().send_to(payload, dest)
+    }
+
+    pub async fn send(&mut self, payload: &[u8]) -> Result<usize> {
+        self.inner.get_mut().send(payload)
+    }
+
+    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        self.read(|socket| socket.recv_from(buffer)).await
+    }
+
+    pub async fn recv_from_meta(&mut self, buffer: &mut [u8]) -> Result<RecvMeta> {
+        self.read(|socket| socket.recv_from_meta(buffer)).await
+    }
+
+    pub async fn recv_msg(&mut self, buffer: &mut [u8]) -> Result<RecvMeta> {
+        self.read(|socket| socket.recv_msg(buffer)).await
+    }
+
+    pub async fn recv(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.read(|socket| socket.recv(buffer)).await
+    }
+
+    // 読み込み可能になるまで待ってから f を呼び出す
+    // 受信できるものが無くなったら (WouldBlock) 準備状態を戻して再び待つ
+    async fn read<F, R>(&mut self, mut f: F) -> Result<R>
+    where
+        F: FnMut(&mut UdpSocket) -> Result<R>,
+    {
+        loop {
+            let mut guard = self.inner.readable_mut().await?;
+            let result = guard.try_io(|inner| match f(inner.get_mut()) {
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
+                result => Ok(result),
+            });
+            if let Ok(result) = result {
+                return result?;
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// ソケットがパケットを送受信する経路
+// pnet の raw チャネル以外 (メモリ上のネットワークや TUN など) でも UdpSocket を使えるようにする
+use crate::port;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::time::{Instant, SystemTime};
+
+// 受信したデータグラムのアドレス
+// UDPパケット本体は Backend::packet で取り出す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Datagram {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    // 受信したパケットの TTL (IPv6 では Hop Limit)。経路が知らない場合は None
+    pub ttl: Option<u8>,
+    // 受信したパケットの TOS (IPv6 では Traffic Class)。経路が知らない場合は None
+    pub tos: Option<u8>,
+    // 到着したインターフェースのインデックス。経路が知らない場合は None
+    pub interface: Option<u32>,
+    // 受信した時刻。経路が知らない場合は None
+    pub timestamp: Option<SystemTime>,
+}
+
+impl Datagram {
+    pub fn new(source: IpAddr, destination: IpAddr) -> Self {
+        Self {
+            source,
+            destination,
+            ttl: None,
+            tos: None,
+            interface: None,
+            timestamp: None,
+        }
+    }
+}
+
+pub trait Backend: Send {
+    // 指定したアドレスのファミリを扱っているかどうか
+    fn supports(&self, ip: IpAddr) -> bool;
+
+    // IPv4 を扱うかどうかを切り替える (:: にバインドしたソケットのデュアルスタック用)
+    fn set_v4(&mut self, _enabled: bool) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // 0.0.0.0 や :: にバインドしたソケットが宛先に送る時の送信元アドレス
+    // 自分のアドレスを持つ経路が実装する。None の場合はホストの経路表で選ぶ
+    fn source_for(&self, _destination: IpAddr) -> io::Result<Option<IpAddr>> {
+        Ok(None)
+    }
+
+    // UDPパケット (ヘッダとチェックサムは設定済み) を送信元から宛先に送る
+    fn send(&mut self, packet: &[u8], source: IpAddr, destination: IpAddr) -> io::Result<usize>;
+
+    // 複数のUDPパケットを (パケット, 送信元, 宛先) の順に送り、送信できた数を返す
+    // 途中で失敗した場合はそこまでの数を返し、最初のパケットで失敗した場合だけエラーを返す
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr, IpAddr)]) -> io::Result<usize> {
+        for (i, &(packet, source, destination)) in packets.iter().enumerate() {
+            if let Err(e) = self.send(packet, source, destination) {
+                return if i == 0 { Err(e) } else { Ok(i) };
+            }
+        }
+        Ok(packets.len())
+    }
+
+    // データグラムを1つ受信する。期限までに受信できなければ None を返す
+    fn recv(&mut self, deadline: Option<Instant>) -> io::Result<Option<Datagram>>;
+
+    // 既に届いているデータグラムを max 個まで待たずに受信する。届いていなければ空を返す
+    // UDPパケットは batch_packet で取り出す。壊れていたパケットは None になる
+    // 既定では recv で1つだけ受信する
+    fn recv_batch(&mut self, max: usize) -> io::Result<Vec<Option<Datagram>>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        match self.recv(Some(Instant::now())) {
+            Ok(datagram) => Ok(datagram.into_iter().map(Some).collect()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(vec![None]),
+            Err(e) => Err(e),
+        }
+    }
+
+    // 送信するパケットの断片化を禁止するかどうか (IPv4 では DF ビットを立てる)
+    fn set_dont_fragment(&mut self, _enabled: bool) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // 宛先までの経路MTU (IP ヘッダを含めて1つのパケットで送れる大きさ)
+    fn path_mtu(&mut self, _destination: IpAddr) -> io::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // ユニキャストで送信するパケットの TTL (IPv6 では Hop Limit)
+    fn set_ttl(&mut self, _ttl: u8) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // 送信するパケットの TOS (IPv6 では Traffic Class)。上位 6 ビットが DSCP、下位 2 ビットが ECN
+    fn set_tos(&mut self, _tos: u8) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // ブロードキャストアドレスへの送信を許可するかどうか
+    // 255.255.255.255 は UdpSocket でも検査する。サブネットのブロードキャストアドレスは経路が検査する
+    fn set_broadcast(&mut self, _enabled: bool) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // IPv4 のマルチキャストグループに参加する
+    // interface は参加するインターフェースのアドレスで、0.0.0.0 なら経路で選ぶ
+    fn join_multicast_v4(&mut self, _group: Ipv4Addr, _interface: Ipv4Addr) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    fn leave_multicast_v4(&mut self, _group: Ipv4Addr, _interface: Ipv4Addr) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // 送信するマルチキャストの TTL (既定値は 1)
+    fn set_multicast_ttl_v4(&mut self, _ttl: u8) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // 送信したマルチキャストを自分にも配送するかどうか (既定値は有効)
+    fn set_multicast_loop_v4(&mut self, _enabled: bool) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // 受信できるものが届くと読み込み可能になるディスクリプタ (イベントループに登録する)
+    // 空の場合は recv で待つしかない
+    fn fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    // 受信するデータグラムを宛先ポートと送信元 (connect した通信相手) で絞り込む
+    // カーネルで絞り込める経路だけが実装する (受信したものは UdpSocket でも検証する)
+    fn set_filter(&mut self, _port: u16, _peer: Option<SocketAddr>) -> io::Result<()> {
+        Ok(())
+    }
+
+    // connect した通信相手を知らせる
+    // ICMP エラーを受信する経路は、通信相手から返ってきた Port Unreachable だけを報告する
+    fn set_peer(&mut self, _peer: SocketAddr) -> io::Result<()> {
+        Ok(())
+    }
+
+    // 受信キューが溢れて捨てたデータグラムの数
+    fn queue_overflow(&self) -> u64 {
+        0
+    }
+
+    // 最後に受信したデータグラムのUDPパケット部分
+    fn packet(&self) -> &[u8];
+
+    // 最後の recv_batch で受信した index 番目のデータグラムのUDPパケット部分
+    fn batch_packet(&self, _index: usize) -> &[u8] {
+        self.packet()
+    }
+
+    // ソケットにポートを割り当てる。0 の場合はエフェメラルポートから選ぶ
+    fn bind_port(&mut self, ip: IpAddr, port: u16) -> io::Result<u16> {
+        port::allocate(ip, port)
+    }
+
+    // ソケットの破棄時にポートを解放する
+    fn release_port(&mut self, port: u16) {
+        port::release(port)
+    }
+}
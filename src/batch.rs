@@ -0,0 +1,113 @@
+// 複数のデータグラムをまとめて送受信する
+// パケットを組み立てるバッファや送信元アドレスの解決をデータグラムの間で共有する
+use crate::{
+    check_payload, write_header, DropReason, Error, RecvMeta, Result, UdpSocket, UDP_HEADER_SIZE,
+};
+use std::io;
+use std::net::SocketAddr;
+
+// recv_batch でデータグラムを1つ受信する領域
+pub struct RecvSlot {
+    buffer: Vec<u8>,
+    meta: Option<RecvMeta>,
+}
+
+impl RecvSlot {
+    // capacity バイトまでのペイロードを受信できる領域を作る。超えた分は切り詰める
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: vec![0; capacity],
+            meta: None,
+        }
+    }
+
+    // 受信したデータグラムの情報。直前の recv_batch で受信しなかった場合は None
+    pub fn meta(&self) -> Option<RecvMeta> {
+        self.meta
+    }
+
+    // 受信したペイロード (バッファにコピーした部分)
+    pub fn payload(&self) -> &[u8] {
+        self.meta.map_or(&[], |meta| &self.buffer[..meta.len])
+    }
+}
+
+impl UdpSocket {
+    // 複数のデータグラムを送信し、送信できた数を返す
+    // 不正なペイロードや宛先が含まれていれば1つも送信せずにエラーを返す
+    // 途中で送信に失敗した場合はそこまでの数を返す (最初のデータグラムで失敗した場合はエラー)
+    pub fn send_batch(&mut self, datagrams: &[(&[u8], SocketAddr)]) -> Result<usize> {
+        let mut headers = Vec::with_capacity(datagrams.len());
+        let mut total = 0;
+        for &(payload, dest) in datagrams {
+            let length = check_payload(payload)?;
+            if !self.link.supports(dest.ip()) {
+                return Err(Error::UnsupportedFamily(dest));
+            }
+            self.check_broadcast(dest)?;
+            let source = self.source_addr_for(dest)?;
+            headers.push((total..total + length, source));
+            total += length;
+        }
+        // send_to と同じバッファを使い、足りなければ広げる
+        if self.send_buffer.len() < total {
+            self.send_buffer.resize(total, 0);
+        }
+        for (&(payload, dest), (range, source)) in datagrams.iter().zip(&headers) {
+            let packet = &mut self.send_buffer[range.clone()];
+            packet[UDP_HEADER_SIZE..].copy_from_slice(payload);
+            write_header(packet, self.port, *source, dest)?;
+        }
+        let buffer = &self.send_buffer;
+        let packets = datagrams
+            .iter()
+            .zip(&headers)
+            .map(|(&(_, dest), (range, source))| (&buffer[range.clone()], *source, dest.ip()))
+            .collect::<Vec<_>>();
+        self.link.send_batch(&packets).map_err(Error::Send)
+    }
+
+    // 複数のデータグラムを受信し、受信した数を返す
+    // 最初の1つは recv_from と同じように待ち、残りは既に届いているものだけを経路からまとめて受信する
+    pub fn recv_batch(&mut self, slots: &mut [RecvSlot]) -> Result<usize> {
+        for slot in slots.iter_mut() {
+            slot.meta = None;
+        }
+        let first = match slots.first_mut() {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+        first.meta = Some(self.recv_from_meta(&mut first.buffer)?);
+        let mut count = 1;
+        while count < slots.len() {
+            let datagrams = match self.link.recv_batch(slots.len() - count) {
+                Ok(datagrams) if datagrams.is_empty() => break,
+                Ok(datagrams) => datagrams,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // 受信したデータグラムを先に返し、エラーは次の受信で返す
+                Err(e) => {
+                    self.pending_error = Some(e);
+                    break;
+                }
+            };
+            for (i, datagram) in datagrams.iter().enumerate() {
+                let datagram = match datagram {
+                    Some(datagram) => datagram,
+                    None => {
+                        self.drop_packet(DropReason::Malformed, false);
+                        continue;
+                    }
+                };
+                let slot = &mut slots[count];
+                match self.accept(datagram, self.link.batch_packet(i), &mut slot.buffer) {
+                    Ok(meta) => {
+                        slot.meta = Some(meta);
+                        count += 1;
+                    }
+                    Err(reason) => self.drop_batch_packet(reason, i),
+                }
+            }
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,78 @@
+// UdpSocket の操作で起こるエラー
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // アドレスを解決しても何も得られなかった
+    InvalidAddress,
+    // ソケットが扱っていないアドレスファミリを指定した (IPv4 のソケットから IPv6 の宛先に送信するなど)
+    UnsupportedFamily(SocketAddr),
+    // UDPパケットを組み立てるバッファが足りない
+    BufferTooSmall,
+    // ペイロードが1つのデータグラムに収まらない
+    PayloadTooLarge(usize),
+    // raw ソケットを開く権限が無い (CAP_NET_RAW が必要)
+    PermissionDenied(io::Error),
+    // パケットの送信に失敗した
+    Send(io::Error),
+    // その他のI/Oエラー
+    Io(io::Error),
+}
+
+impl Error {
+    // std::net と同じように扱えるよう対応する io::ErrorKind を返す
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::InvalidAddress | Error::BufferTooSmall | Error::PayloadTooLarge(_) => {
+                io::ErrorKind::InvalidInput
+            }
+            Error::UnsupportedFamily(_) => io::ErrorKind::Unsupported,
+            Error::PermissionDenied(e) | Error::Send(e) | Error::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidAddress => write!(f, "invalid socket address"),
+            Error::UnsupportedFamily(addr) => {
+                write!(f, "address family of {} is not supported by socket", addr)
+            }
+            Error::BufferTooSmall => write!(f, "packet buffer too small"),
+            Error::PayloadTooLarge(len) => write!(f, "payload of {} bytes is too large", len),
+            Error::PermissionDenied(e) => write!(f, "permission denied opening raw socket: {}", e),
+            Error::Send(e) => write!(f, "failed to send: {}", e),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::PermissionDenied(e) | Error::Send(e) | Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::PermissionDenied(e) | Error::Send(e) | Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
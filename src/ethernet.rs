@@ -0,0 +1,535 @@
+// pnet の datalink チャネルで Ethernet フレームを直接送受信するバックエンド
+// IPv4 ヘッダに加えて Ethernet ヘッダもユーザ空間で組み立て、宛先の MAC アドレスは
+// 経路表で決めた次のホストを ARP で解決して得る
+use crate::arp::{self, Message};
+use crate::backend::{Backend, Datagram};
+use crate::fragment::{self, Reassembler};
+use crate::icmp::{self, Listener};
+use crate::igmp::{self, Memberships};
+use crate::ipv4::{self, Header};
+use crate::port;
+use crate::sys;
+use crate::{Error, Result};
+use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, NetworkInterface};
+use pnet::ipnetwork::{IpNetwork, Ipv4Network};
+use pnet::packet::arp::ArpOperations;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant, SystemTime};
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+// FCS を除いた最小のフレーム長。短いフレームは 0 で埋める
+const MIN_FRAME_SIZE: usize = 60;
+// ARP 要求を送り直す回数と応答を待つ時間
+const ARP_RETRIES: usize = 3;
+const ARP_TIMEOUT: Duration = Duration::from_secs(1);
+// ARP の応答を待つ間に受信したパケットを溜めておく数
+const BACKLOG_SIZE: usize = 64;
+
+// 経路表のエントリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub network: Ipv4Network,
+    // None の場合は宛先がリンク上にある
+    pub gateway: Option<Ipv4Addr>,
+    // この経路で送るパケットを断片化する大きさ。None の場合はインターフェースの MTU
+    pub mtu: Option<usize>,
+}
+
+impl Route {
+    pub fn new(network: Ipv4Network, gateway: Option<Ipv4Addr>) -> Self {
+        Self {
+            network,
+            gateway,
+            mtu: None,
+        }
+    }
+}
+
+// 受信したフレームの中身
+enum Frame {
+    Arp(Message),
+    Ipv4(Vec<u8>),
+}
+
+pub struct EthernetBackend {
+    interface: NetworkInterface,
+    mac: MacAddr,
+    local_ip: Ipv4Addr,
+    mtu: usize,
+    // 受信した ICMP エラー
+    errors: Listener,
+    dont_fragment: bool,
+    // ユニキャストで送信するパケットの TTL と、送信するパケットの TOS
+    ttl: u8,
+    tos: u8,
+    // ブロードキャストアドレスへの送信を許可するかどうか
+    broadcast: bool,
+    sender: Box<dyn DataLinkSender>,
+    receiver: Box<dyn DataLinkReceiver>,
+    // 受信を待つための datalink チャネルのディスクリプタ (チャネルが閉じる)
+    fd: RawFd,
+    routes: Vec<Route>,
+    arp: arp::Cache,
+    // ARP の解決中に受信した IPv4 パケットと、自分に配送するマルチキャスト
+    backlog: VecDeque<Vec<u8>>,
+    // backlog に入れたら読み込み可能にする eventfd
+    ready: OwnedFd,
+    multicast: Memberships,
+    reassembler: Reassembler,
+    // IPv4 ヘッダの識別子
+    next_id: u16,
+    packet_buffer: Vec<u8>,
+    frame_buffer: Vec<u8>,
+    // 最後に受信した IPv4 パケットとその中のUDPパケットの範囲
+    current: Vec<u8>,
+    last: Range<usize>,
+}
+
+impl EthernetBackend {
+    // インターフェースの datalink チャネルを開き、local_ip を自分のアドレスとして送受信する
+    // インターフェースに設定されているネットワークは直接つながる経路として登録する
+    pub fn open(interface_name: &str, local_ip: Ipv4Addr) -> Result<Self> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == interface_name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such interface: {}", interface_name),
+                )
+            })?;
+        let mac = interface.mac.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} has no MAC address", interface_name),
+            )
+        })?;
+        let fd = sys::packet_socket().map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(e),
+            _ => Error::Io(e),
+        })?;
+        let config = Config {
+            socket_fd: Some(fd),
+            ..Config::default()
+        };
+        let (sender, receiver) = match datalink::channel(&interface, config) {
+            Ok(Channel::Ethernet(sender, receiver)) => (sender, receiver),
+            Ok(_) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unknown datalink channel type",
+                )))
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return Err(Error::PermissionDenied(e))
+            }
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let routes = interface
+            .ips
+            .iter()
+            .filter_map(|ip| match ip {
+                IpNetwork::V4(network) => Some(Route::new(
+                    Ipv4Network::new(network.network(), network.prefix()).unwrap(),
+                    None,
+                )),
+                IpNetwork::V6(_) => None,
+            })
+            .collect();
+        let mtu = sys::interface_mtu(interface_name).unwrap_or(fragment::DEFAULT_MTU);
+        Ok(Self {
+            interface,
+            mac,
+            local_ip,
+            mtu,
+            errors: Listener::new(),
+            dont_fragment: false,
+            ttl: ipv4::DEFAULT_TTL,
+            tos: 0,
+            broadcast: false,
+            sender,
+            receiver,
+            fd,
+            routes,
+            arp: arp::Cache::new(),
+            backlog: VecDeque::new(),
+            ready: sys::eventfd()?,
+            multicast: Memberships::new(),
+            reassembler: Reassembler::new(),
+            next_id: 0,
+            packet_buffer: Vec::new(),
+            frame_buffer: Vec::new(),
+            current: Vec::new(),
+            last: 0..0,
+        })
+    }
+
+    pub fn interface(&self) -> &NetworkInterface {
+        &self.interface
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    // 経路の MTU を指定しなかった場合に使う、インターフェースを開いた時の MTU
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+
+    // 経路を追加する。同じネットワークの経路は置き換える
+    pub fn add_route(&mut self, mut route: Route) {
+        route.network = Ipv4Network::new(route.network.network(), route.network.prefix()).unwrap();
+        self.routes.retain(|other| other.network != route.network);
+        self.routes.push(route);
+    }
+
+    pub fn set_default_gateway(&mut self, gateway: Ipv4Addr) {
+        self.add_route(Route::new(
+            Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap(),
+            Some(gateway),
+        ));
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // 宛先に最も長く一致する経路
+    fn route(&self, destination: Ipv4Addr) -> io::Result<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.network.contains(destination))
+            .max_by_key(|route| route.network.prefix())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NetworkUnreachable,
+                    format!("no route to {}", destination),
+                )
+            })
+    }
+
+    // 経路の MTU と ICMP で知った経路MTUの小さい方
+    fn mtu_for(&mut self, destination: Ipv4Addr) -> usize {
+        let mtu = self
+            .route(destination)
+            .ok()
+            .and_then(|route| route.mtu)
+            .unwrap_or(self.mtu);
+        self.errors
+            .pmtu
+            .get(IpAddr::V4(destination))
+            .map_or(mtu, |pmtu| pmtu.min(mtu))
+    }
+
+    // 255.255.255.255 か、直接つながるネットワークのブロードキャストアドレス
+    fn is_broadcast(&self, destination: Ipv4Addr) -> bool {
+        destination.is_broadcast()
+            || self.routes.iter().any(|route| {
+                // /31 と /32 にはブロードキャストアドレスが無い
+                route.gateway.is_none()
+                    && route.network.prefix() < 31
+                    && route.network.broadcast() == destination
+            })
+    }
+
+    // 宛先のフレームの送り先の MAC アドレスを決める
+    fn resolve(&mut self, destination: Ipv4Addr) -> io::Result<MacAddr> {
+        if self.is_broadcast(destination) {
+            return Ok(MacAddr::broadcast());
+        }
+        if destination.is_multicast() {
+            // 01:00:5e に下位 23 ビットを続ける
+            let [_, b, c, d] = destination.octets();
+            return Ok(MacAddr::new(0x01, 0x00, 0x5e, b & 0x7f, c, d));
+        }
+        let route = self.route(destination)?;
+        let next_hop = route.gateway.unwrap_or(destination);
+        if let Some(mac) = self.arp.get(next_hop) {
+            return Ok(mac);
+        }
+        let request = Message::request(self.mac, self.local_ip, next_hop);
+        for _ in 0..ARP_RETRIES {
+            self.send_arp(&request, MacAddr::broadcast())?;
+            let deadline = Instant::now() + ARP_TIMEOUT;
+            while let Some(frame) = self.next_frame(Some(deadline))? {
+                match frame {
+                    Frame::Arp(message) => self.handle_arp(&message)?,
+                    Frame::Ipv4(packet) => self.enqueue(packet),
+                }
+                if let Some(mac) = self.arp.get(next_hop) {
+                    return Ok(mac);
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::HostUnreachable,
+            format!("ARP request for {} timed out", next_hop),
+        ))
+    }
+
+    // 自分宛ての要求に応答し、送信元のアドレスを覚える
+    fn handle_arp(&mut self, message: &Message) -> io::Result<()> {
+        let for_us = message.target_ip == self.local_ip;
+        if !message.sender_ip.is_unspecified() && (for_us || self.arp.contains(message.sender_ip)) {
+            self.arp.insert(message.sender_ip, message.sender_mac);
+        }
+        if for_us && message.operation == ArpOperations::Request {
+            self.send_arp(&message.reply(self.mac), message.sender_mac)?;
+        }
+        Ok(())
+    }
+
+    // 後で受信するパケットを backlog に入れ、eventfd で読み込み可能にする
+    fn enqueue(&mut self, packet: Vec<u8>) {
+        if self.backlog.len() < BACKLOG_SIZE {
+            self.backlog.push_back(packet);
+            sys::eventfd_notify(self.ready.as_raw_fd());
+        }
+    }
+
+    fn send_igmp(&mut self, message: Option<igmp::Message>) -> io::Result<()> {
+        if let Some((destination, packet)) = message {
+            let mac = self.resolve(destination)?;
+            self.send_frame(mac, EtherTypes::Ipv4, &packet)?;
+        }
+        Ok(())
+    }
+
+    fn send_arp(&mut self, message: &Message, destination: MacAddr) -> io::Result<()> {
+        let mut payload = [0; arp::PACKET_SIZE];
+        message.write(&mut payload);
+        self.send_frame(destination, EtherTypes::Arp, &payload)
+    }
+
+    fn send_frame(
+        &mut self,
+        destination: MacAddr,
+        ethertype: EtherType,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let len = (ETHERNET_HEADER_SIZE + payload.len()).max(MIN_FRAME_SIZE);
+        self.frame_buffer.clear();
+        self.frame_buffer.resize(len, 0);
+        let mut frame = MutableEthernetPacket::new(&mut self.frame_buffer).unwrap();
+        frame.set_destination(destination);
+        frame.set_source(self.mac);
+        frame.set_ethertype(ethertype);
+        self.frame_buffer[ETHERNET_HEADER_SIZE..][..payload.len()].copy_from_slice(payload);
+        self.sender
+            .send_to(&self.frame_buffer, None)
+            .unwrap_or_else(|| Err(io::Error::other("failed to send frame")))
+    }
+
+    // 自分宛てまたはブロードキャスト・マルチキャストの ARP か IPv4 のフレームを1つ受信する
+    // 期限までに受信できなければ None を返す
+    fn next_frame(&mut self, deadline: Option<Instant>) -> io::Result<Option<Frame>> {
+        loop {
+            // 期限を過ぎていても (ノンブロッキングや recv_batch でも) 既に届いているフレームは受信する
+            if sys::poll_readable(&[self.fd], deadline)?.is_none() {
+                return Ok(None);
+            }
+            let frame = self.receiver.next()?;
+            let frame = match EthernetPacket::new(frame) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let destination = frame.get_destination();
+            // マルチキャストアドレスは最初のオクテットの最下位ビットが 1
+            if destination != self.mac && destination.0 & 1 == 0 {
+                continue;
+            }
+            match frame.get_ethertype() {
+                EtherTypes::Arp => {
+                    if let Some(message) = Message::parse(frame.payload()) {
+                        return Ok(Some(Frame::Arp(message)));
+                    }
+                }
+                EtherTypes::Ipv4 => return Ok(Some(Frame::Ipv4(frame.payload().to_vec()))),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Backend for EthernetBackend {
+    fn supports(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4()
+    }
+
+    // ホストの経路表ではなく、このリンク上の自分のアドレスから送る
+    fn source_for(&self, _destination: IpAddr) -> io::Result<Option<IpAddr>> {
+        Ok(Some(IpAddr::V4(self.local_ip)))
+    }
+
+    fn send(&mut self, packet: &[u8], source: IpAddr, destination: IpAddr) -> io::Result<usize> {
+        self.errors.take_error()?;
+        let (source, destination) = match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => (source, destination),
+            _ => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+        if !self.broadcast && self.is_broadcast(destination) {
+            // カーネルと同じく EACCES を返す
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+        let mac = self.resolve(destination)?;
+        let mtu = self.mtu_for(destination);
+        let header = Header {
+            source,
+            destination,
+            id: self.next_id,
+            ttl: if destination.is_multicast() {
+                self.multicast.ttl
+            } else {
+                self.ttl
+            },
+            tos: self.tos,
+            dont_fragment: self.dont_fragment,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+        // 自分が参加しているグループ宛てなら、受信したものとして backlog にも入れる
+        let loopback = self.multicast.loopback && self.multicast.is_member(destination);
+        let mut buffer = std::mem::take(&mut self.packet_buffer);
+        let result = fragment::fragment(&mut buffer, &header, packet, mtu, |fragment| {
+            self.send_frame(mac, EtherTypes::Ipv4, fragment)?;
+            if loopback {
+                self.enqueue(fragment.to_vec());
+            }
+            Ok(())
+        });
+        self.packet_buffer = buffer;
+        result.map(|()| packet.len())
+    }
+
+    fn set_dont_fragment(&mut self, enabled: bool) -> io::Result<()> {
+        self.dont_fragment = enabled;
+        Ok(())
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.tos = tos;
+        Ok(())
+    }
+
+    fn set_broadcast(&mut self, enabled: bool) -> io::Result<()> {
+        self.broadcast = enabled;
+        Ok(())
+    }
+
+    // インターフェースは1つなので、自分のアドレスか 0.0.0.0 だけを指定できる
+    fn join_multicast_v4(&mut self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        if !interface.is_unspecified() && interface != self.local_ip {
+            return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+        }
+        let report = self.multicast.join(group, self.local_ip)?;
+        self.send_igmp(report)
+    }
+
+    fn leave_multicast_v4(&mut self, group: Ipv4Addr, _interface: Ipv4Addr) -> io::Result<()> {
+        let leave = self.multicast.leave(group)?;
+        self.send_igmp(leave)
+    }
+
+    fn set_multicast_ttl_v4(&mut self, ttl: u8) -> io::Result<()> {
+        self.multicast.ttl = ttl;
+        Ok(())
+    }
+
+    fn set_multicast_loop_v4(&mut self, enabled: bool) -> io::Result<()> {
+        self.multicast.loopback = enabled;
+        Ok(())
+    }
+
+    fn path_mtu(&mut self, destination: IpAddr) -> io::Result<usize> {
+        match destination {
+            IpAddr::V4(destination) => Ok(self.mtu_for(destination)),
+            IpAddr::V6(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    // ARP と IGMP の Query は処理し、UDP 以外の IPv4 パケットは読み飛ばす
+    // 断片化されたパケットは揃うまで待ち、ICMP エラーは経路MTUの更新と Port Unreachable の通知に使う
+    // マルチキャストは参加しているグループ宛てのものだけ受信する
+    fn recv(&mut self, deadline: Option<Instant>) -> io::Result<Option<Datagram>> {
+        loop {
+            let packet = match self.backlog.pop_front() {
+                Some(packet) => {
+                    if self.backlog.is_empty() {
+                        sys::eventfd_clear(self.ready.as_raw_fd());
+                    }
+                    packet
+                }
+                None => match self.next_frame(deadline)? {
+                    Some(Frame::Ipv4(packet)) => packet,
+                    Some(Frame::Arp(message)) => {
+                        self.handle_arp(&message)?;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+            let packet = if ipv4::parse(&packet)?.is_some_and(|packet| ipv4::is_fragment(&packet)) {
+                match self.reassembler.reassemble(&packet)? {
+                    Some(reassembled) => reassembled,
+                    None => continue,
+                }
+            } else {
+                packet
+            };
+            if let Some((mut datagram, range)) = ipv4::decapsulate(&packet)? {
+                if !self.multicast.accepts(datagram.destination) {
+                    continue;
+                }
+                // datalink チャネルはカーネルの受信時刻を渡さないので、ソフトウェアの時刻を使う
+                datagram.interface = Some(self.interface.index);
+                datagram.timestamp = Some(SystemTime::now());
+                self.current = packet;
+                self.last = range;
+                return Ok(Some(datagram));
+            }
+            for report in self.multicast.answer(&packet) {
+                self.send_igmp(Some(report))?;
+            }
+            if let Some(report) = icmp::parse_v4(&packet) {
+                self.errors.handle(&report);
+                self.errors.take_error()?;
+            }
+        }
+    }
+
+    fn bind_port(&mut self, ip: IpAddr, port: u16) -> io::Result<u16> {
+        let port = port::allocate(ip, port)?;
+        self.errors.set_port(port);
+        Ok(port)
+    }
+
+    fn set_peer(&mut self, peer: SocketAddr) -> io::Result<()> {
+        self.errors.set_peer(peer);
+        Ok(())
+    }
+
+    // 自分宛てでないフレームでも読み込み可能になるので、recv が None を返すこともある
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.fd, self.ready.as_raw_fd()]
+    }
+
+    fn packet(&self) -> &[u8] {
+        &self.current[self.last.clone()]
+    }
+}
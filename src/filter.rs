@@ -0,0 +1,268 @@
+// raw チャネルにアタッチする classic BPF のプログラム
+// 宛先ポート (と connect した通信相手) が一致するUDPパケットだけをカーネルから受け取る
+use libc::sock_filter;
+use std::net::SocketAddr;
+
+// 返り値は受け取るバイト数。0 なら捨てる
+const ACCEPT: u32 = u32::MAX;
+const REJECT: u32 = 0;
+// IPv6 ヘッダ中の送信元アドレスの位置
+const IPV6_SOURCE_OFFSET: i32 = 8;
+
+// 一致しなければ REJECT に飛ぶ比較を含むプログラム
+struct Program {
+    instructions: Vec<sock_filter>,
+    // 一致しない時に REJECT へ飛ぶ命令の位置
+    checks: Vec<usize>,
+}
+
+impl Program {
+    fn new() -> Self {
+        Self {
+            instructions: Vec::new(),
+            checks: Vec::new(),
+        }
+    }
+
+    fn statement(&mut self, code: u32, k: u32) {
+        self.instructions.push(sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        });
+    }
+
+    // アキュムレータが value と等しくなければ捨てる
+    fn expect(&mut self, value: u32) {
+        self.checks.push(self.instructions.len());
+        self.statement(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, value);
+    }
+
+    // 末尾に ACCEPT と REJECT を置き、比較の飛び先を REJECT にする
+    fn finish(mut self) -> Vec<sock_filter> {
+        self.statement(libc::BPF_RET | libc::BPF_K, ACCEPT);
+        self.statement(libc::BPF_RET | libc::BPF_K, REJECT);
+        let reject = self.instructions.len() - 1;
+        for i in self.checks {
+            self.instructions[i].jf = (reject - i - 1) as u8;
+        }
+        self.instructions
+    }
+}
+
+// IPv4 の raw ソケットでは IP ヘッダから始まるパケットに対して実行される
+pub(crate) fn v4(port: u16, peer: Option<SocketAddr>) -> Vec<sock_filter> {
+    let mut program = Program::new();
+    let peer = match peer {
+        Some(SocketAddr::V4(peer)) => Some(peer),
+        // IPv4 では届かない通信相手
+        Some(SocketAddr::V6(_)) => return reject_all(),
+        None => None,
+    };
+    // X = IP ヘッダの長さ
+    program.statement(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 0);
+    program.statement(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, 2);
+    program.expect(port as u32);
+    if let Some(peer) = peer {
+        program.statement(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, 0);
+        program.expect(peer.port() as u32);
+        program.statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 12);
+        program.expect(u32::from(*peer.ip()));
+    }
+    program.finish()
+}
+
+// IPv6 の raw ソケットではUDPヘッダから始まるので、送信元アドレスは SKF_NET_OFF で IP ヘッダから読む
+pub(crate) fn v6(port: u16, peer: Option<SocketAddr>) -> Vec<sock_filter> {
+    let mut program = Program::new();
+    let peer = match peer {
+        Some(SocketAddr::V6(peer)) => Some(peer),
+        Some(SocketAddr::V4(_)) => return reject_all(),
+        None => None,
+    };
+    program.statement(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 2);
+    program.expect(port as u32);
+    if let Some(peer) = peer {
+        program.statement(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 0);
+        program.expect(peer.port() as u32);
+        for (i, word) in peer.ip().octets().chunks(4).enumerate() {
+            let offset = libc::SKF_NET_OFF + IPV6_SOURCE_OFFSET + i as i32 * 4;
+            program.statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset as u32);
+            program.expect(u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+        }
+    }
+    program.finish()
+}
+
+// 何も受け取らないプログラム
+pub(crate) fn reject_all() -> Vec<sock_filter> {
+    vec![sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: REJECT,
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    const PEER_V4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const PEER_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+    // カーネルと同じように classic BPF を実行する (プログラムが使う命令だけ)
+    // packet はソケットが受け取る部分、network は SKF_NET_OFF で読む IP ヘッダから始まる部分
+    // パケットの外を読むとカーネルと同じく捨てる
+    fn run(program: &[sock_filter], packet: &[u8], network: &[u8]) -> u32 {
+        let load = |offset: i64, size: usize| -> Option<u32> {
+            let net_offset = libc::SKF_NET_OFF as i64;
+            let (buffer, offset) = if (net_offset..0).contains(&offset) {
+                (network, offset - net_offset)
+            } else {
+                (packet, offset)
+            };
+            let bytes = buffer.get(offset as usize..offset as usize + size)?;
+            Some(
+                bytes
+                    .iter()
+                    .fold(0, |value, &byte| value << 8 | byte as u32),
+            )
+        };
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0);
+        loop {
+            let instruction = program[pc];
+            let k = instruction.k;
+            pc += 1;
+            let loaded = match instruction.code as u32 {
+                code if code == libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH => {
+                    load(k as i64, 1).map(|byte| x = (byte & 0xf) * 4)
+                }
+                code if code == libc::BPF_LD | libc::BPF_H | libc::BPF_IND => {
+                    load(x as i64 + k as i64, 2).map(|value| a = value)
+                }
+                code if code == libc::BPF_LD | libc::BPF_H | libc::BPF_ABS => {
+                    load(k as i32 as i64, 2).map(|value| a = value)
+                }
+                code if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                    load(k as i32 as i64, 4).map(|value| a = value)
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    let offset = if a == k {
+                        instruction.jt
+                    } else {
+                        instruction.jf
+                    };
+                    pc += offset as usize;
+                    Some(())
+                }
+                code if code == libc::BPF_RET | libc::BPF_K => return k,
+                code => panic!("unexpected instruction {:#x}", code),
+            };
+            if loaded.is_none() {
+                return REJECT;
+            }
+        }
+    }
+
+    fn udp(source_port: u16, destination_port: u16) -> Vec<u8> {
+        let mut buffer = vec![0; 12];
+        buffer[0..2].copy_from_slice(&source_port.to_be_bytes());
+        buffer[2..4].copy_from_slice(&destination_port.to_be_bytes());
+        buffer
+    }
+
+    // options バイトのオプションを持つ IPv4 パケット
+    fn packet_v4(source: Ipv4Addr, options: usize, udp: &[u8]) -> Vec<u8> {
+        let length = 20 + options;
+        let mut buffer = vec![0; length];
+        buffer[0] = 0x40 | (length / 4) as u8;
+        buffer[9] = libc::IPPROTO_UDP as u8;
+        buffer[12..16].copy_from_slice(&source.octets());
+        buffer.extend_from_slice(udp);
+        buffer
+    }
+
+    fn header_v6(source: Ipv6Addr) -> Vec<u8> {
+        let mut buffer = vec![0; 40];
+        buffer[0] = 0x60;
+        buffer[6] = libc::IPPROTO_UDP as u8;
+        buffer[8..24].copy_from_slice(&source.octets());
+        buffer
+    }
+
+    fn accepts_v4(program: &[sock_filter], source: Ipv4Addr, udp: &[u8]) -> bool {
+        let packet = packet_v4(source, 0, udp);
+        run(program, &packet, &packet) == ACCEPT
+    }
+
+    fn accepts_v6(program: &[sock_filter], source: Ipv6Addr, udp: &[u8]) -> bool {
+        let mut network = header_v6(source);
+        network.extend_from_slice(udp);
+        run(program, udp, &network) == ACCEPT
+    }
+
+    #[test]
+    fn v4_matches_port() {
+        let program = v4(5000, None);
+        assert_eq!(program.len(), 5);
+        assert!(accepts_v4(&program, PEER_V4, &udp(6000, 5000)));
+        assert!(!accepts_v4(&program, PEER_V4, &udp(6000, 5001)));
+        // IP ヘッダの長さはオプションの分だけ変わる
+        let packet = packet_v4(PEER_V4, 8, &udp(6000, 5000));
+        assert_eq!(run(&program, &packet, &packet), ACCEPT);
+    }
+
+    #[test]
+    fn v4_matches_peer() {
+        let program = v4(5000, Some(SocketAddr::new(IpAddr::V4(PEER_V4), 6000)));
+        assert_eq!(program.len(), 9);
+        assert!(accepts_v4(&program, PEER_V4, &udp(6000, 5000)));
+        assert!(!accepts_v4(&program, PEER_V4, &udp(6001, 5000)));
+        assert!(!accepts_v4(
+            &program,
+            Ipv4Addr::new(10, 0, 0, 3),
+            &udp(6000, 5000)
+        ));
+        assert!(!accepts_v4(&program, PEER_V4, &udp(6000, 5001)));
+        // IPv6 の通信相手からは IPv4 で届かない
+        let program = v4(5000, Some(SocketAddr::new(IpAddr::V6(PEER_V6), 6000)));
+        assert!(!accepts_v4(&program, PEER_V4, &udp(6000, 5000)));
+    }
+
+    #[test]
+    fn v6_matches_port_and_peer() {
+        let program = v6(5000, None);
+        assert_eq!(program.len(), 4);
+        assert!(accepts_v6(&program, PEER_V6, &udp(6000, 5000)));
+        assert!(!accepts_v6(&program, PEER_V6, &udp(6000, 5001)));
+        let program = v6(5000, Some(SocketAddr::new(IpAddr::V6(PEER_V6), 6000)));
+        assert_eq!(program.len(), 14);
+        assert!(accepts_v6(&program, PEER_V6, &udp(6000, 5000)));
+        assert!(!accepts_v6(&program, PEER_V6, &udp(6001, 5000)));
+        // アドレスの最後の 4 バイトだけ違う
+        let other = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3);
+        assert!(!accepts_v6(&program, other, &udp(6000, 5000)));
+        let program = v6(5000, Some(SocketAddr::new(IpAddr::V4(PEER_V4), 6000)));
+        assert!(!accepts_v6(&program, PEER_V6, &udp(6000, 5000)));
+    }
+
+    #[test]
+    fn checks_jump_to_reject() {
+        let program = v6(5000, Some(SocketAddr::new(IpAddr::V6(PEER_V6), 6000)));
+        let reject = program.len() - 1;
+        assert_eq!(program[reject].k, REJECT);
+        assert_eq!(program[reject - 1].k, ACCEPT);
+        let checks = program
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| instruction.code as u32 & 0x07 == libc::BPF_JMP);
+        assert_eq!(checks.clone().count(), 6);
+        for (i, instruction) in checks {
+            assert_eq!(instruction.jt, 0);
+            assert_eq!(i + 1 + instruction.jf as usize, reject);
+        }
+    }
+}
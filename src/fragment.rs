@@ -0,0 +1,349 @@
+// ユーザ空間で送受信するバックエンド向けの IPv4 の断片化と再構築
+use crate::ipv4::{self, Header};
+use pnet::packet::ipv4::{self as ipv4_packet, Ipv4Flags, MutableIpv4Packet};
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+// RFC 791 で全てのホストが扱えることになっている最小の MTU
+pub(crate) const MIN_MTU: usize = 68;
+pub(crate) const DEFAULT_MTU: usize = 1500;
+// 最初の断片を受信してから揃うまで待つ時間 (Linux の ipfrag_time と同じ)
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// 再構築中のデータグラムに使うメモリの上限。超えたら古いものから捨てる
+const MEMORY_LIMIT: usize = 4 * 1024 * 1024;
+
+// UDPパケットを MTU に収まる IPv4 パケットに分割し、1つずつ emit に渡す
+// 断片のデータ長は最後のものを除いて 8 の倍数にする
+pub(crate) fn fragment<F>(
+    buffer: &mut Vec<u8>,
+    header: &Header,
+    payload: &[u8],
+    mtu: usize,
+    mut emit: F,
+) -> io::Result<()>
+where
+    F: FnMut(&[u8]) -> io::Result<()>,
+{
+    if ipv4::HEADER_SIZE + payload.len() <= mtu {
+        ipv4::encapsulate(buffer, header, payload)?;
+        return emit(buffer);
+    }
+    if header.dont_fragment {
+        // カーネルと同じく EMSGSIZE を返す
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+    if mtu < MIN_MTU {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("MTU {} is smaller than {}", mtu, MIN_MTU),
+        ));
+    }
+    let size = (mtu - ipv4::HEADER_SIZE) & !7;
+    for (i, data) in payload.chunks(size).enumerate() {
+        let offset = i * size;
+        let more_fragments = offset + data.len() < payload.len();
+        ipv4::encapsulate_fragment(buffer, header, offset, more_fragments, data)?;
+        emit(buffer)?;
+    }
+    Ok(())
+}
+
+// 断片を識別する送信元・宛先・プロトコル・識別子
+type Key = (Ipv4Addr, Ipv4Addr, u8, u16);
+
+// 再構築中のデータグラム
+struct Pending {
+    // オフセット 0 の断片のヘッダ
+    header: Option<Vec<u8>>,
+    data: Vec<u8>,
+    // 受信済みの範囲 (昇順で、隣接するものはまとめる)
+    received: Vec<Range<usize>>,
+    // 最後の断片を受信して分かったデータの長さ
+    total: Option<usize>,
+    expires_at: Instant,
+}
+
+impl Pending {
+    fn is_complete(&self) -> bool {
+        self.header.is_some()
+            && self
+                .total
+                .is_some_and(|total| match self.received.as_slice() {
+                    [range] => range.start == 0 && range.end == total,
+                    _ => false,
+                })
+    }
+
+    fn insert(&mut self, range: Range<usize>) {
+        self.received.push(range);
+        self.received.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.received.len());
+        for range in self.received.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => merged.push(range),
+            }
+        }
+        self.received = merged;
+    }
+}
+
+pub(crate) struct Reassembler {
+    pending: HashMap<Key, Pending>,
+    // 再構築中のデータの合計バイト数
+    memory: usize,
+}
+
+impl Reassembler {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            memory: 0,
+        }
+    }
+
+    // 断片を1つ受け取り、全て揃ったら組み立てた IPv4 パケットを返す
+    // 一部が重なる断片を受信した場合はデータグラムごと捨てる (重複した断片は無視する)
+    pub(crate) fn reassemble(&mut self, buffer: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.expire();
+        let packet = match ipv4::parse(buffer)? {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+        let key = (
+            packet.get_source(),
+            packet.get_destination(),
+            packet.get_next_level_protocol().0,
+            packet.get_identification(),
+        );
+        let header_length = packet.get_header_length() as usize * 4;
+        let data = &buffer[header_length..packet.get_total_length() as usize];
+        let offset = packet.get_fragment_offset() as usize * 8;
+        let end = offset + data.len();
+        let more_fragments = packet.get_flags() & Ipv4Flags::MoreFragments != 0;
+        if header_length + end > u16::MAX as usize
+            || (more_fragments && !data.len().is_multiple_of(8))
+        {
+            self.discard(&key);
+            return Err(ipv4::invalid("invalid IPv4 fragment"));
+        }
+        let now = Instant::now();
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            header: None,
+            data: Vec::new(),
+            received: Vec::new(),
+            total: None,
+            expires_at: now + REASSEMBLY_TIMEOUT,
+        });
+        if pending
+            .received
+            .iter()
+            .any(|range| range.start <= offset && end <= range.end)
+        {
+            return Ok(None);
+        }
+        let overlaps = pending
+            .received
+            .iter()
+            .any(|range| range.start < end && offset < range.end);
+        let inconsistent = match (more_fragments, pending.total) {
+            (false, Some(total)) => total != end,
+            (false, None) => pending.received.last().is_some_and(|last| last.end > end),
+            (true, Some(total)) => end > total,
+            (true, None) => false,
+        };
+        if overlaps || inconsistent {
+            self.discard(&key);
+            return Err(ipv4::invalid("overlapping or inconsistent IPv4 fragments"));
+        }
+        let growth = end.saturating_sub(pending.data.len());
+        if growth > 0 {
+            pending.data.resize(end, 0);
+        }
+        pending.data[offset..end].copy_from_slice(data);
+        pending.insert(offset..end);
+        if !more_fragments {
+            pending.total = Some(end);
+        }
+        if offset == 0 {
+            pending.header = Some(buffer[..header_length].to_vec());
+        }
+        self.memory += growth;
+        if pending.is_complete() {
+            let pending = self.pending.remove(&key).unwrap();
+            self.memory -= pending.data.len();
+            return Ok(Some(assemble(pending)));
+        }
+        self.enforce_limit(&key);
+        Ok(None)
+    }
+
+    fn discard(&mut self, key: &Key) {
+        if let Some(pending) = self.pending.remove(key) {
+            self.memory -= pending.data.len();
+        }
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.expires_at <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.discard(&key);
+        }
+    }
+
+    // 上限を超えている間は current 以外の古いデータグラムから捨てる
+    fn enforce_limit(&mut self, current: &Key) {
+        while self.memory > MEMORY_LIMIT {
+            let oldest = self
+                .pending
+                .iter()
+                .filter(|(key, _)| *key != current)
+                .min_by_key(|(_, pending)| pending.expires_at)
+                .map(|(key, _)| *key);
+            match oldest {
+                Some(key) => self.discard(&key),
+                None => break,
+            }
+        }
+    }
+}
+
+// 最初の断片のヘッダにデータをつなげ、断片化していないパケットとして組み立てる
+fn assemble(pending: Pending) -> Vec<u8> {
+    let mut buffer = pending.header.unwrap();
+    buffer.extend_from_slice(&pending.data);
+    let total_length = buffer.len() as u16;
+    let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+    packet.set_total_length(total_length);
+    packet.set_flags(packet.get_flags() & Ipv4Flags::DontFragment);
+    packet.set_fragment_offset(0);
+    let checksum = ipv4_packet::checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(id: u16) -> Header {
+        Header {
+            source: Ipv4Addr::new(10, 0, 0, 1),
+            destination: Ipv4Addr::new(10, 0, 0, 2),
+            id,
+            ttl: ipv4::DEFAULT_TTL,
+            tos: 0,
+            dont_fragment: false,
+        }
+    }
+
+    fn payload(length: usize) -> Vec<u8> {
+        (0..length).map(|i| i as u8).collect()
+    }
+
+    // payload を mtu で断片化した IPv4 パケット
+    fn fragments(id: u16, payload: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut fragments = Vec::new();
+        fragment(&mut buffer, &header(id), payload, mtu, |fragment| {
+            fragments.push(fragment.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        fragments
+    }
+
+    // 断片化しなかった場合の IPv4 パケット
+    fn whole(id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        ipv4::encapsulate(&mut buffer, &header(id), payload).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn reassembles_fragments_out_of_order() {
+        let payload = payload(3000);
+        let mut fragments = fragments(1, &payload, 1000);
+        assert_eq!(fragments.len(), 4);
+        fragments.swap(0, 2);
+        fragments.reverse();
+        let mut reassembler = Reassembler::new();
+        let last = fragments.pop().unwrap();
+        for fragment in &fragments {
+            assert_eq!(reassembler.reassemble(fragment).unwrap(), None);
+        }
+        // 重複した断片は無視する
+        assert_eq!(reassembler.reassemble(&fragments[0]).unwrap(), None);
+        assert_eq!(
+            reassembler.reassemble(&last).unwrap(),
+            Some(whole(1, &payload))
+        );
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.memory, 0);
+    }
+
+    #[test]
+    fn discards_overlapping_fragments() {
+        let payload = payload(3000);
+        // 976 バイトずつと 480 バイトずつの断片は 960..976 で重なる
+        let large = fragments(1, &payload, 1000);
+        let small = fragments(1, &payload, 500);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.reassemble(&large[0]).unwrap(), None);
+        let error = reassembler.reassemble(&small[2]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.memory, 0);
+        // 捨てた後の断片だけでは揃わない
+        for fragment in &large[1..] {
+            assert_eq!(reassembler.reassemble(fragment).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn limits_memory_for_incomplete_datagrams() {
+        let payload = payload(60000);
+        let mut reassembler = Reassembler::new();
+        let count = MEMORY_LIMIT / payload.len() + 10;
+        for id in 0..count as u16 {
+            // 最初の断片が届かないデータグラムは末尾までの領域を確保したままになる
+            let last = fragments(id, &payload, 1500).pop().unwrap();
+            assert_eq!(reassembler.reassemble(&last).unwrap(), None);
+            assert!(reassembler.memory <= MEMORY_LIMIT);
+        }
+        assert!(reassembler.pending.len() < count);
+        // 古いものから捨て、最後に受信したデータグラムは残す
+        let latest = count as u16 - 1;
+        assert!(reassembler
+            .pending
+            .keys()
+            .any(|&(_, _, _, id)| id == latest));
+    }
+
+    #[test]
+    fn evicts_expired_datagrams() {
+        let payload = payload(3000);
+        let fragments = fragments(1, &payload, 1000);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.reassemble(&fragments[0]).unwrap(), None);
+        // REASSEMBLY_TIMEOUT が経過したことにする
+        for pending in reassembler.pending.values_mut() {
+            pending.expires_at = Instant::now() - Duration::from_millis(1);
+        }
+        for fragment in &fragments[1..] {
+            assert_eq!(reassembler.reassemble(fragment).unwrap(), None);
+        }
+        assert_eq!(reassembler.pending.len(), 1);
+        // 最初の断片は捨てたので、残りの断片は揃わない
+        assert_eq!(reassembler.memory, payload.len());
+    }
+}
@@ -0,0 +1,399 @@
+// 送信したUDPデータグラムについて返ってきた ICMP エラーの解析と、
+// 閉じているポート宛てのデータグラムに返す ICMP エラーの組み立て
+use crate::backend::Datagram;
+use crate::pmtu::PmtuCache;
+use pnet::packet::icmp::{self, IcmpCode, IcmpPacket, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::icmpv6::{Icmpv6Code, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::udp::UdpPacket;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+// ICMP ヘッダ (タイプ・コード・チェックサムと 4 バイトのフィールド) の長さ
+const HEADER_SIZE: usize = 8;
+const IPV6_HEADER_SIZE: usize = 40;
+// 次ホップの MTU を返さないルータのために、元のパケットより小さい値を推定する
+// (RFC 1191 の表)
+const PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+// 返す ICMP エラーの長さの上限 (IPv4 は RFC 1812、IPv6 は最小MTU に収まる長さ)
+const MAX_ERROR_SIZE_V4: usize = 576 - 20;
+const MAX_ERROR_SIZE_V6: usize = 1280 - IPV6_HEADER_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    // 経路上で断片化が必要になった (IPv6 では Packet Too Big)。次ホップの MTU を持つ
+    FragmentationNeeded(usize),
+    // 宛先のポートでソケットが開いていなかった
+    PortUnreachable,
+}
+
+// ICMP エラーとその原因になったデータグラムのアドレス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Report {
+    pub(crate) kind: Kind,
+    pub(crate) source: SocketAddr,
+    pub(crate) destination: SocketAddr,
+}
+
+impl Report {
+    // カーネルと同じく、Port Unreachable は connect したソケットに通信相手から返ってきたものだけ報告する
+    // (raw ソケットだけが使っているポートにはホストのカーネルも Port Unreachable を返すため)
+    pub(crate) fn refuses(&self, port: u16, peer: Option<SocketAddr>) -> bool {
+        self.kind == Kind::PortUnreachable
+            && self.source.port() == port
+            && peer == Some(self.destination)
+    }
+}
+
+// IPv4 ヘッダ付きの ICMP パケットを解析する
+pub(crate) fn parse_v4(buffer: &[u8]) -> Option<Report> {
+    let ip_packet = Ipv4Packet::new(buffer)?;
+    if ip_packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+        return None;
+    }
+    let start = ip_packet.get_header_length() as usize * 4;
+    let end = (ip_packet.get_total_length() as usize).min(buffer.len());
+    let packet = IcmpPacket::new(buffer.get(start..end)?)?;
+    if packet.get_checksum() != icmp::checksum(&packet) {
+        return None;
+    }
+    let body = &buffer[start..end];
+    if body.len() < HEADER_SIZE {
+        return None;
+    }
+    let kind = match (packet.get_icmp_type(), packet.get_icmp_code()) {
+        (IcmpTypes::DestinationUnreachable, IcmpCode(3)) => Kind::PortUnreachable,
+        (IcmpTypes::DestinationUnreachable, IcmpCode(4)) => {
+            Kind::FragmentationNeeded(u16::from_be_bytes([body[6], body[7]]) as usize)
+        }
+        _ => return None,
+    };
+    let original = Ipv4Packet::new(&body[HEADER_SIZE..])?;
+    if original.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let kind = match kind {
+        Kind::FragmentationNeeded(mtu)
+            if mtu == 0 || mtu >= original.get_total_length() as usize =>
+        {
+            Kind::FragmentationNeeded(plateau(original.get_total_length() as usize))
+        }
+        kind => kind,
+    };
+    let offset = HEADER_SIZE + original.get_header_length() as usize * 4;
+    let udp_packet = UdpPacket::new(body.get(offset..)?)?;
+    Some(Report {
+        kind,
+        source: SocketAddr::new(IpAddr::V4(original.get_source()), udp_packet.get_source()),
+        destination: SocketAddr::new(
+            IpAddr::V4(original.get_destination()),
+            udp_packet.get_destination(),
+        ),
+    })
+}
+
+// IPv6 の raw ソケットではヘッダ無しで受信し、チェックサムはカーネルが検証する
+pub(crate) fn parse_v6(buffer: &[u8]) -> Option<Report> {
+    let packet = Icmpv6Packet::new(buffer)?;
+    if buffer.len() < HEADER_SIZE {
+        return None;
+    }
+    let kind = match packet.get_icmpv6_type() {
+        Icmpv6Types::DestinationUnreachable if packet.get_icmpv6_code() == Icmpv6Code(4) => {
+            Kind::PortUnreachable
+        }
+        Icmpv6Types::PacketTooBig => Kind::FragmentationNeeded(u32::from_be_bytes([
+            buffer[4], buffer[5], buffer[6], buffer[7],
+        ]) as usize),
+        _ => return None,
+    };
+    let original = Ipv6Packet::new(&buffer[HEADER_SIZE..])?;
+    // 拡張ヘッダは辿らない
+    if original.get_next_header() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let udp_packet = UdpPacket::new(buffer.get(HEADER_SIZE + IPV6_HEADER_SIZE..)?)?;
+    Some(Report {
+        kind,
+        source: SocketAddr::new(IpAddr::V6(original.get_source()), udp_packet.get_source()),
+        destination: SocketAddr::new(
+            IpAddr::V6(original.get_destination()),
+            udp_packet.get_destination(),
+        ),
+    })
+}
+
+fn plateau(length: usize) -> usize {
+    PLATEAUS
+        .iter()
+        .copied()
+        .find(|&mtu| mtu < length)
+        .unwrap_or(PLATEAUS[PLATEAUS.len() - 1])
+}
+
+// IPv4 ヘッダ付きで受信したUDPデータグラムに対する Port Unreachable を組み立てる
+pub(crate) fn port_unreachable_v4(original: &[u8]) -> Vec<u8> {
+    let original = &original[..original.len().min(MAX_ERROR_SIZE_V4 - HEADER_SIZE)];
+    let mut buffer = vec![0; HEADER_SIZE + original.len()];
+    let mut packet = MutableIcmpPacket::new(&mut buffer).unwrap();
+    packet.set_icmp_type(IcmpTypes::DestinationUnreachable);
+    packet.set_icmp_code(IcmpCode(3));
+    buffer[HEADER_SIZE..].copy_from_slice(original);
+    let checksum = icmp::checksum(&IcmpPacket::new(&buffer).unwrap());
+    MutableIcmpPacket::new(&mut buffer)
+        .unwrap()
+        .set_checksum(checksum);
+    buffer
+}
+
+// IPv6 の raw ソケットではヘッダを受信できないので、アドレスから組み立て直して引用する
+// チェックサムはカーネルが計算する
+pub(crate) fn port_unreachable_v6(datagram: &Datagram, udp_packet: &[u8]) -> Vec<u8> {
+    let (source, destination) = match (datagram.source, datagram.destination) {
+        (IpAddr::V6(source), IpAddr::V6(destination)) => (source, destination),
+        _ => return Vec::new(),
+    };
+    let length = (HEADER_SIZE + IPV6_HEADER_SIZE + udp_packet.len()).min(MAX_ERROR_SIZE_V6);
+    let mut buffer = vec![0; length];
+    let mut header = MutableIpv6Packet::new(&mut buffer[HEADER_SIZE..]).unwrap();
+    header.set_version(6);
+    header.set_payload_length(udp_packet.len() as u16);
+    header.set_next_header(IpNextHeaderProtocols::Udp);
+    header.set_hop_limit(64);
+    header.set_source(source);
+    header.set_destination(destination);
+    let quoted = length - HEADER_SIZE - IPV6_HEADER_SIZE;
+    buffer[HEADER_SIZE + IPV6_HEADER_SIZE..].copy_from_slice(&udp_packet[..quoted]);
+    let mut packet = MutableIcmpv6Packet::new(&mut buffer).unwrap();
+    packet.set_icmpv6_type(Icmpv6Types::DestinationUnreachable);
+    packet.set_icmpv6_code(Icmpv6Code(4));
+    buffer
+}
+
+// ブロードキャストやマルチキャスト宛て、送信元の分からないデータグラムにはエラーを返さない
+pub(crate) fn should_reply(datagram: &Datagram) -> bool {
+    let broadcast = match datagram.destination {
+        IpAddr::V4(ip) => ip.is_broadcast() || ip.is_multicast(),
+        IpAddr::V6(ip) => ip.is_multicast(),
+    };
+    !broadcast && !datagram.source.is_unspecified() && !datagram.source.is_multicast()
+}
+
+// 受信した ICMP エラーのうち、ソケットに関係するものを覚えておく
+pub(crate) struct Listener {
+    pub(crate) pmtu: PmtuCache,
+    // バインドしたポート
+    port: u16,
+    // connect した通信相手
+    peer: Option<SocketAddr>,
+    // 次の送受信で返すエラー
+    refused: bool,
+}
+
+impl Listener {
+    pub(crate) fn new() -> Self {
+        Self {
+            pmtu: PmtuCache::new(),
+            port: 0,
+            peer: None,
+            refused: false,
+        }
+    }
+
+    pub(crate) fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    pub(crate) fn set_peer(&mut self, peer: SocketAddr) {
+        self.peer = Some(peer);
+    }
+
+    // 経路MTUは宛先毎に全てのポートで共有し、Port Unreachable はこのポートから通信相手に送ったものだけ扱う
+    pub(crate) fn handle(&mut self, report: &Report) {
+        match report.kind {
+            Kind::FragmentationNeeded(mtu) => self.pmtu.update(report.destination.ip(), mtu),
+            Kind::PortUnreachable if report.refuses(self.port, self.peer) => self.refused = true,
+            Kind::PortUnreachable => {}
+        }
+    }
+
+    // Port Unreachable を受信していればエラーを1度だけ返す
+    pub(crate) fn take_error(&mut self) -> io::Result<()> {
+        if std::mem::take(&mut self.refused) {
+            return Err(refused());
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn refused() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, "ICMP port unreachable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipv4::{self, Header};
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::udp::MutableUdpPacket;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const LOCAL_V4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const PEER_V4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const LOCAL_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    const PEER_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+    // ポート 5000 から 6000 に送った length バイトのUDPパケット
+    fn udp(length: usize) -> Vec<u8> {
+        let mut buffer = vec![0; length];
+        let mut packet = MutableUdpPacket::new(&mut buffer).unwrap();
+        packet.set_source(5000);
+        packet.set_destination(6000);
+        packet.set_length(length as u16);
+        buffer
+    }
+
+    fn encapsulate(source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        let header = Header {
+            source,
+            destination,
+            id: 1,
+            ttl: ipv4::DEFAULT_TTL,
+            tos: 0,
+            dont_fragment: true,
+        };
+        let mut buffer = Vec::new();
+        ipv4::encapsulate(&mut buffer, &header, payload).unwrap();
+        buffer
+    }
+
+    // 通信相手から返ってきた IPv4 の Destination Unreachable (IPv4 ヘッダ付き)
+    fn error_v4(code: u8, mtu: u16, original: &[u8]) -> Vec<u8> {
+        let mut message = port_unreachable_v4(original);
+        message[1] = code;
+        message[6..8].copy_from_slice(&mtu.to_be_bytes());
+        let checksum = icmp::checksum(&IcmpPacket::new(&message).unwrap());
+        MutableIcmpPacket::new(&mut message)
+            .unwrap()
+            .set_checksum(checksum);
+        let mut buffer = encapsulate(PEER_V4, LOCAL_V4, &message);
+        MutableIpv4Packet::new(&mut buffer)
+            .unwrap()
+            .set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        buffer
+    }
+
+    fn refusal_v4() -> Report {
+        Report {
+            kind: Kind::PortUnreachable,
+            source: SocketAddr::new(IpAddr::V4(LOCAL_V4), 5000),
+            destination: SocketAddr::new(IpAddr::V4(PEER_V4), 6000),
+        }
+    }
+
+    #[test]
+    fn parses_port_unreachable_v4() {
+        let sent = encapsulate(LOCAL_V4, PEER_V4, &udp(100));
+        let mut error = error_v4(3, 0, &sent);
+        assert_eq!(parse_v4(&error), Some(refusal_v4()));
+        // チェックサムの合わないものは無視する
+        let last = error.len() - 1;
+        error[last] ^= 0xff;
+        assert_eq!(parse_v4(&error), None);
+    }
+
+    #[test]
+    fn estimates_plateau_without_next_hop_mtu() {
+        let sent = encapsulate(LOCAL_V4, PEER_V4, &udp(1480));
+        let mtu = |next_hop| parse_v4(&error_v4(4, next_hop, &sent)).map(|report| report.kind);
+        assert_eq!(mtu(1400), Some(Kind::FragmentationNeeded(1400)));
+        // 0 や元のパケット以上の値は信用せず、1500 バイトより小さい値を表から選ぶ
+        assert_eq!(mtu(0), Some(Kind::FragmentationNeeded(1492)));
+        assert_eq!(mtu(1500), Some(Kind::FragmentationNeeded(1492)));
+    }
+
+    #[test]
+    fn parses_v6() {
+        let sent = Datagram::new(IpAddr::V6(LOCAL_V6), IpAddr::V6(PEER_V6));
+        let mut error = port_unreachable_v6(&sent, &udp(100));
+        let source = SocketAddr::new(IpAddr::V6(LOCAL_V6), 5000);
+        let destination = SocketAddr::new(IpAddr::V6(PEER_V6), 6000);
+        assert_eq!(
+            parse_v6(&error),
+            Some(Report {
+                kind: Kind::PortUnreachable,
+                source,
+                destination,
+            })
+        );
+        // Packet Too Big は 4 バイトのフィールドに MTU を持つ
+        error[0] = Icmpv6Types::PacketTooBig.0;
+        error[1] = 0;
+        error[4..8].copy_from_slice(&1400u32.to_be_bytes());
+        assert_eq!(
+            parse_v6(&error),
+            Some(Report {
+                kind: Kind::FragmentationNeeded(1400),
+                source,
+                destination,
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_only_for_connected_peer() {
+        let report = refusal_v4();
+        let peer = report.destination;
+        assert!(report.refuses(5000, Some(peer)));
+        assert!(!report.refuses(5000, None));
+        assert!(!report.refuses(5000, Some(SocketAddr::new(peer.ip(), 6001))));
+        assert!(!report.refuses(5001, Some(peer)));
+        let report = Report {
+            kind: Kind::FragmentationNeeded(1400),
+            ..report
+        };
+        assert!(!report.refuses(5000, Some(peer)));
+    }
+
+    #[test]
+    fn builds_port_unreachable_v4() {
+        let received = encapsulate(PEER_V4, LOCAL_V4, &udp(1000));
+        let error = port_unreachable_v4(&received);
+        // 576 バイトの IPv4 パケットに収まるように引用を切り詰める
+        assert_eq!(error.len(), MAX_ERROR_SIZE_V4);
+        assert_eq!(
+            &error[HEADER_SIZE..],
+            &received[..error.len() - HEADER_SIZE]
+        );
+        let packet = IcmpPacket::new(&error).unwrap();
+        assert_eq!(packet.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(packet.get_icmp_code(), IcmpCode(3));
+        assert_eq!(packet.get_checksum(), icmp::checksum(&packet));
+    }
+
+    #[test]
+    fn builds_port_unreachable_v6() {
+        let received = Datagram::new(IpAddr::V6(PEER_V6), IpAddr::V6(LOCAL_V6));
+        let udp_packet = udp(2000);
+        let error = port_unreachable_v6(&received, &udp_packet);
+        // 最小MTU の IPv6 パケットに収まるように引用を切り詰める
+        assert_eq!(error.len(), MAX_ERROR_SIZE_V6);
+        let packet = Icmpv6Packet::new(&error).unwrap();
+        assert_eq!(
+            packet.get_icmpv6_type(),
+            Icmpv6Types::DestinationUnreachable
+        );
+        assert_eq!(packet.get_icmpv6_code(), Icmpv6Code(4));
+        let quoted = Ipv6Packet::new(&error[HEADER_SIZE..]).unwrap();
+        assert_eq!(quoted.get_source(), PEER_V6);
+        assert_eq!(quoted.get_destination(), LOCAL_V6);
+        assert_eq!(quoted.get_payload_length(), 2000);
+        let offset = HEADER_SIZE + IPV6_HEADER_SIZE;
+        assert_eq!(&error[offset..], &udp_packet[..error.len() - offset]);
+        // IPv4 のアドレスには組み立てない
+        let received = Datagram::new(IpAddr::V4(PEER_V4), IpAddr::V4(LOCAL_V4));
+        assert!(port_unreachable_v6(&received, &udp_packet).is_empty());
+    }
+}
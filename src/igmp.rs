@@ -0,0 +1,145 @@
+// ユーザ空間で送受信するバックエンド向けの IGMPv2 (RFC 2236)
+// 参加しているマルチキャストグループを覚えておき、Membership Report の送信と Query への応答を行う
+use crate::ipv4;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self as ipv4_packet, MutableIpv4Packet};
+use pnet::packet::Packet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+
+const MEMBERSHIP_QUERY: u8 = 0x11;
+const MEMBERSHIP_REPORT: u8 = 0x16;
+const LEAVE_GROUP: u8 = 0x17;
+const MESSAGE_SIZE: usize = 8;
+// IGMP のパケットに付ける Router Alert オプション (RFC 2113)
+const ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0x00, 0x00];
+const ALL_HOSTS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+const ALL_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 2);
+// マルチキャストの TTL の既定値 (カーネルと同じ)
+const DEFAULT_TTL: u8 = 1;
+
+// 宛先と IPv4 パケット
+pub(crate) type Message = (Ipv4Addr, Vec<u8>);
+
+// 参加しているグループと、送信するマルチキャストの設定
+pub(crate) struct Memberships {
+    // グループと Report の送信元にするアドレス
+    groups: Vec<(Ipv4Addr, Ipv4Addr)>,
+    // 送信するマルチキャストの TTL
+    pub(crate) ttl: u8,
+    // 送信したマルチキャストを自分でも受信するかどうか
+    pub(crate) loopback: bool,
+}
+
+impl Memberships {
+    pub(crate) fn new() -> Self {
+        Self {
+            groups: Vec::new(),
+            ttl: DEFAULT_TTL,
+            loopback: true,
+        }
+    }
+
+    // グループに参加し、送信する Membership Report を返す
+    // 全ホストのグループ (224.0.0.1) には Report を送らない
+    pub(crate) fn join(
+        &mut self,
+        group: Ipv4Addr,
+        source: Ipv4Addr,
+    ) -> io::Result<Option<Message>> {
+        if self.is_member(group) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+        self.groups.push((group, source));
+        Ok(report(group, source))
+    }
+
+    // グループから離脱し、送信する Leave Group を返す
+    pub(crate) fn leave(&mut self, group: Ipv4Addr) -> io::Result<Option<Message>> {
+        let i = self
+            .groups
+            .iter()
+            .position(|&(joined, _)| joined == group)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+        let (_, source) = self.groups.remove(i);
+        if group == ALL_HOSTS {
+            return Ok(None);
+        }
+        Ok(Some((
+            ALL_ROUTERS,
+            message(LEAVE_GROUP, source, ALL_ROUTERS, group),
+        )))
+    }
+
+    pub(crate) fn is_member(&self, group: Ipv4Addr) -> bool {
+        self.groups.iter().any(|&(joined, _)| joined == group)
+    }
+
+    // マルチキャストは参加しているグループと全ホスト宛てのものだけ受信する
+    pub(crate) fn accepts(&self, destination: IpAddr) -> bool {
+        match destination {
+            IpAddr::V4(destination) => {
+                !destination.is_multicast()
+                    || destination == ALL_HOSTS
+                    || self.is_member(destination)
+            }
+            IpAddr::V6(_) => true,
+        }
+    }
+
+    // 受信したパケットが Membership Query であれば、応答する Membership Report を返す
+    // 応答を遅らせる時間 (Max Resp Time) は使わず、すぐに応答する
+    pub(crate) fn answer(&self, buffer: &[u8]) -> Vec<Message> {
+        let packet = match ipv4::parse(buffer) {
+            Ok(Some(packet)) if packet.get_next_level_protocol() == IpNextHeaderProtocols::Igmp => {
+                packet
+            }
+            _ => return Vec::new(),
+        };
+        let igmp = packet.payload();
+        if igmp.len() < MESSAGE_SIZE
+            || igmp[0] != MEMBERSHIP_QUERY
+            || pnet::util::checksum(igmp, 1) != u16::from_be_bytes([igmp[2], igmp[3]])
+        {
+            return Vec::new();
+        }
+        // 0.0.0.0 なら全てのグループへの General Query
+        let queried = Ipv4Addr::new(igmp[4], igmp[5], igmp[6], igmp[7]);
+        self.groups
+            .iter()
+            .filter(|&&(group, _)| queried.is_unspecified() || group == queried)
+            .filter_map(|&(group, source)| report(group, source))
+            .collect()
+    }
+}
+
+fn report(group: Ipv4Addr, source: Ipv4Addr) -> Option<Message> {
+    if group == ALL_HOSTS {
+        return None;
+    }
+    Some((group, message(MEMBERSHIP_REPORT, source, group, group)))
+}
+
+// TTL 1 で Router Alert オプションを付けた IGMP メッセージの IPv4 パケットを組み立てる
+fn message(kind: u8, source: Ipv4Addr, destination: Ipv4Addr, group: Ipv4Addr) -> Vec<u8> {
+    let header_length = ipv4::HEADER_SIZE + ROUTER_ALERT.len();
+    let total_length = header_length + MESSAGE_SIZE;
+    let mut buffer = vec![0; total_length];
+    let igmp = &mut buffer[header_length..];
+    igmp[0] = kind;
+    igmp[4..].copy_from_slice(&group.octets());
+    let checksum = pnet::util::checksum(igmp, 1);
+    igmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    buffer[ipv4::HEADER_SIZE..header_length].copy_from_slice(&ROUTER_ALERT);
+    let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+    packet.set_version(4);
+    packet.set_header_length((header_length / 4) as u8);
+    packet.set_total_length(total_length as u16);
+    packet.set_ttl(1);
+    packet.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    let checksum = ipv4_packet::checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+    buffer
+}
@@ -0,0 +1,120 @@
+// ユーザ空間で送受信するバックエンド向けの IPv4 ヘッダの組み立てと解析
+use crate::backend::Datagram;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::Range;
+
+// オプション無しのヘッダの長さ
+pub(crate) const HEADER_SIZE: usize = 20;
+pub(crate) const DEFAULT_TTL: u8 = 64;
+
+// 送信するパケットのヘッダに設定する値
+pub(crate) struct Header {
+    pub(crate) source: Ipv4Addr,
+    pub(crate) destination: Ipv4Addr,
+    pub(crate) id: u16,
+    pub(crate) ttl: u8,
+    pub(crate) tos: u8,
+    // DF ビットを立てる
+    pub(crate) dont_fragment: bool,
+}
+
+// UDPパケットを IPv4 ヘッダで包んで buffer に書き込む
+pub(crate) fn encapsulate(buffer: &mut Vec<u8>, header: &Header, payload: &[u8]) -> io::Result<()> {
+    encapsulate_fragment(buffer, header, 0, false, payload)
+}
+
+// UDPパケットの offset バイト目からの断片を IPv4 ヘッダで包んで buffer に書き込む
+pub(crate) fn encapsulate_fragment(
+    buffer: &mut Vec<u8>,
+    header: &Header,
+    offset: usize,
+    more_fragments: bool,
+    data: &[u8],
+) -> io::Result<()> {
+    let total_length = HEADER_SIZE + data.len();
+    if HEADER_SIZE + offset + data.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "datagram too large for IPv4",
+        ));
+    }
+    buffer.clear();
+    buffer.resize(total_length, 0);
+    let mut packet = MutableIpv4Packet::new(buffer).unwrap();
+    packet.set_version(4);
+    packet.set_header_length((HEADER_SIZE / 4) as u8);
+    packet.set_total_length(total_length as u16);
+    packet.set_identification(header.id);
+    if more_fragments {
+        packet.set_flags(Ipv4Flags::MoreFragments);
+    } else if header.dont_fragment {
+        packet.set_flags(Ipv4Flags::DontFragment);
+    }
+    packet.set_fragment_offset((offset / 8) as u16);
+    packet.set_ttl(header.ttl);
+    packet.set_dscp(header.tos >> 2);
+    packet.set_ecn(header.tos & 0b11);
+    packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    packet.set_source(header.source);
+    packet.set_destination(header.destination);
+    packet.set_payload(data);
+    let checksum = ipv4::checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+    Ok(())
+}
+
+// 受信した IPv4 パケットを検証し、UDP であればアドレスとUDPパケットの範囲を返す
+// UDP 以外のプロトコルや断片化されたパケットは None を返す
+pub(crate) fn decapsulate(buffer: &[u8]) -> io::Result<Option<(Datagram, Range<usize>)>> {
+    let packet = match parse(buffer)? {
+        Some(packet) => packet,
+        None => return Ok(None),
+    };
+    if packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp || is_fragment(&packet) {
+        return Ok(None);
+    }
+    let header_length = packet.get_header_length() as usize * 4;
+    let mut datagram = Datagram::new(
+        IpAddr::V4(packet.get_source()),
+        IpAddr::V4(packet.get_destination()),
+    );
+    datagram.ttl = Some(packet.get_ttl());
+    datagram.tos = Some(tos(&packet));
+    Ok(Some((
+        datagram,
+        header_length..packet.get_total_length() as usize,
+    )))
+}
+
+// ヘッダの長さとチェックサムを検証する。IPv4 でなければ None を返す
+pub(crate) fn parse(buffer: &[u8]) -> io::Result<Option<Ipv4Packet<'_>>> {
+    let packet = Ipv4Packet::new(buffer).ok_or_else(|| invalid("truncated IPv4 header"))?;
+    if packet.get_version() != 4 {
+        return Ok(None);
+    }
+    let header_length = packet.get_header_length() as usize * 4;
+    let total_length = packet.get_total_length() as usize;
+    if header_length < HEADER_SIZE || total_length < header_length || total_length > buffer.len() {
+        return Err(invalid("invalid IPv4 header"));
+    }
+    if packet.get_checksum() != ipv4::checksum(&packet) {
+        return Err(invalid("IPv4 header checksum mismatch"));
+    }
+    Ok(Some(packet))
+}
+
+// ヘッダの DSCP と ECN を合わせた TOS
+pub(crate) fn tos(packet: &Ipv4Packet) -> u8 {
+    packet.get_dscp() << 2 | packet.get_ecn()
+}
+
+pub(crate) fn is_fragment(packet: &Ipv4Packet) -> bool {
+    packet.get_flags() & Ipv4Flags::MoreFragments != 0 || packet.get_fragment_offset() != 0
+}
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
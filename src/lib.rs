@@ -1,98 +1,716 @@
-use anyhow::{Context, Result};
-use pnet::packet::{
-    ip::IpNextHeaderProtocols,
-    udp::{self, MutableUdpPacket},
-    Packet,
-};
-use pnet::transport::{
-    self, TransportChannelType, TransportProtocol, TransportReceiver, TransportSender,
-};
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::Packet;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant, SystemTime};
+
+mod arp;
+#[cfg(feature = "tokio")]
+mod async_socket;
+mod backend;
+mod batch;
+mod error;
+mod ethernet;
+mod filter;
+mod fragment;
+mod icmp;
+mod igmp;
+mod ipv4;
+mod pmtu;
+mod port;
+mod raw;
+mod route;
+mod sim;
+#[cfg(feature = "mio")]
+mod source;
+mod stack;
+mod stats;
+mod sys;
+mod tos;
+mod tun;
+
+#[cfg(feature = "tokio")]
+pub use async_socket::AsyncUdpSocket;
+pub use backend::{Backend, Datagram};
+pub use batch::RecvSlot;
+pub use error::{Error, Result};
+pub use ethernet::{EthernetBackend, Route};
+pub use raw::RawBackend;
+pub use sim::{LinkConfig, SimNetwork, SimThread};
+pub use stack::UdpStack;
+pub use stats::{DropReason, Stats};
+pub use tos::Ecn;
+pub use tun::TunBackend;
 
 const UDP_HEADER_SIZE: usize = 8;
-const BUFFER_SIZE: usize = 65535;
-const LOCAL_ADDR: &str = "127.0.0.1";
+
+// 受信したデータグラムの情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvMeta {
+    // バッファにコピーしたバイト数
+    pub len: usize,
+    // データグラムのペイロードの本来の長さ
+    pub original_len: usize,
+    // バッファに収まらずペイロードが切り詰められたかどうか
+    pub truncated: bool,
+    // 送信元のソケットアドレス
+    pub source: SocketAddr,
+    // IP ヘッダの TTL (IPv6 では Hop Limit)。経路が知らない場合は None
+    pub ttl: Option<u8>,
+    // IP ヘッダの TOS (IPv6 では Traffic Class)。経路が知らない場合は None
+    pub tos: Option<u8>,
+    // データグラムの宛先アドレス。0.0.0.0 や :: にバインドしたソケットで返信の送信元を決めるのに使う
+    pub destination: IpAddr,
+    // 到着したインターフェースのインデックス。経路が知らない場合は None
+    pub interface: Option<u32>,
+    // 受信した時刻。raw ソケットではカーネルが受信した時刻 (SO_TIMESTAMPNS)、
+    // ユーザ空間の経路ではパケットを読んだ時刻。経路が知らない場合は None
+    pub timestamp: Option<SystemTime>,
+}
+
+impl RecvMeta {
+    pub fn dscp(&self) -> Option<u8> {
+        self.tos.map(tos::dscp)
+    }
+
+    pub fn ecn(&self) -> Option<Ecn> {
+        self.tos.map(Ecn::from_tos)
+    }
+}
 
 pub struct UdpSocket {
+    // バインドしたローカルアドレス。0.0.0.0 や :: の場合はパケット毎に解決する
+    local_ip: IpAddr,
     port: u16,
-    sender: TransportSender,
-    receiver: TransportReceiver,
+    link: Box<dyn Backend>,
+    // 受信のタイムアウト。None の場合は受信するまで待ち続ける
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+    // connect で指定した通信相手
+    peer: Option<SocketAddr>,
+    // recv_from が切り詰める前の長さを返すかどうか (MSG_TRUNC 相当)
+    recv_trunc: bool,
+    stats: Stats,
+    // パケットを破棄した時に呼び出す関数
+    drop_hook: Option<DropHook>,
+    // 送信するUDPパケットを組み立てるバッファ
+    send_buffer: Vec<u8>,
+    // recv_batch の途中で受け取ったため、次の受信で返すエラー
+    pending_error: Option<io::Error>,
+    // 経路のディスクリプタをまとめた epoll インスタンス (AsRawFd で公開する)
+    poller: OwnedFd,
+    // ブロードキャストアドレスへの送信を許可するかどうか (SO_BROADCAST 相当)
+    broadcast: bool,
+    // 宛先毎に経路表で選んだ送信元アドレス
+    sources: route::SourceCache,
+    // ユニキャストで送信するパケットの TTL と、送信するパケットの TOS
+    ttl: u8,
+    tos: u8,
+    // 送信するマルチキャストの TTL と、自分にも配送するかどうか
+    multicast_ttl_v4: u8,
+    multicast_loop_v4: bool,
 }
 
+// 破棄したUDPパケット(読み込みエラーの場合は空)と理由を受け取る
+pub type DropHook = Box<dyn FnMut(&[u8], DropReason) + Send>;
+
 impl UdpSocket {
     // Socketの初期化
+    // 全てのインターフェース(0.0.0.0)の指定ポートにバインドする
     pub fn new(port: u16) -> Result<Self> {
-        // channel の生成
-        let (sender, receiver) = transport::transport_channel(
-            BUFFER_SIZE,
-            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Udp)),
-        )?;
+        Self::bind((Ipv4Addr::UNSPECIFIED, port))
+    }
+
+    // 指定したローカルアドレスにバインドしたSocketを生成する
+    // :: にバインドした場合はデュアルスタックになり、IPv4 のデータグラムも送受信する
+    // ポート番号に 0 を指定するとエフェメラルポートから空いているものを割り当てる
+    pub fn bind<T: ToSocketAddrs>(addr: T) -> Result<Self> {
+        let addr = resolve(addr)?;
+        let backend = match addr.ip() {
+            IpAddr::V4(_) => RawBackend::new(true, false)?,
+            IpAddr::V6(ip) => RawBackend::new(ip.is_unspecified(), true)?,
+        };
+        Self::with_backend(addr, backend)
+    }
+
+    // 送受信の経路を指定してソケットを生成する
+    pub fn with_backend<T, B>(addr: T, mut backend: B) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+        B: Backend + 'static,
+    {
+        let addr = resolve(addr)?;
+        let port = backend.bind_port(addr.ip(), addr.port())?;
+        Self::with_link(addr.ip(), port, Box::new(backend))
+    }
+
+    // ポートを割り当て済みの経路からソケットを生成する
+    pub(crate) fn with_link(
+        local_ip: IpAddr,
+        port: u16,
+        mut link: Box<dyn Backend>,
+    ) -> Result<Self> {
+        let poller = sys::epoll(&link.fds()).and_then(|poller| {
+            link.set_filter(port, None)?;
+            Ok(poller)
+        });
+        let poller = match poller {
+            Ok(poller) => poller,
+            Err(e) => {
+                link.release_port(port);
+                return Err(e.into());
+            }
+        };
         Ok(Self {
+            local_ip,
             port,
-            sender,
-            receiver,
+            link,
+            read_timeout: None,
+            nonblocking: false,
+            peer: None,
+            recv_trunc: false,
+            stats: Stats::default(),
+            drop_hook: None,
+            send_buffer: Vec::new(),
+            pending_error: None,
+            poller,
+            broadcast: false,
+            sources: route::SourceCache::new(),
+            ttl: ipv4::DEFAULT_TTL,
+            tos: 0,
+            multicast_ttl_v4: 1,
+            multicast_loop_v4: true,
         })
     }
 
+    // バインドしているローカルアドレス
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.local_ip, self.port)
+    }
+
+    // 通信相手を固定する
+    // 以降は send/recv が使えるようになり、通信相手以外からのデータグラムは受信しない
+    pub fn connect<T: ToSocketAddrs>(&mut self, addr: T) -> Result<()> {
+        let addr = resolve(addr)?;
+        self.link.set_filter(self.port, Some(addr))?;
+        self.link.set_peer(addr)?;
+        self.peer = Some(addr);
+        Ok(())
+    }
+
+    // connect した通信相手のアドレス
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.peer
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected).into())
+    }
+
+    // connect した通信相手にUDPデータを送信する
+    pub fn send(&mut self, payload: &[u8]) -> Result<usize> {
+        let peer = self.peer_addr()?;
+        self.send_to(payload, peer)
+    }
+
+    // connect した通信相手からUDPデータを受信する
+    pub fn recv(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.peer_addr()?;
+        self.recv_from(buffer).map(|(n, _)| n)
+    }
+
+    // MSG_TRUNC と同様に recv_from/recv がバッファに収まらなかった分も含めた長さを返すようにする
+    pub fn set_recv_trunc(&mut self, recv_trunc: bool) {
+        self.recv_trunc = recv_trunc;
+    }
+
+    // 受信時に破棄したパケットの数
+    pub fn stats(&self) -> Stats {
+        Stats {
+            queue_overflow: self.link.queue_overflow(),
+            ..self.stats
+        }
+    }
+
+    // パケットを破棄した時に呼び出す関数を設定する
+    pub fn set_drop_hook(&mut self, hook: Option<DropHook>) {
+        self.drop_hook = hook;
+    }
+
+    // 受信のタイムアウトを設定する
+    // タイムアウトすると recv_from は io::ErrorKind::TimedOut のエラーを返す
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot set a 0 duration timeout",
+            )
+            .into());
+        }
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    // ノンブロッキングモードを切り替える
+    // 受信できるデータグラムが無い場合 recv_from は io::ErrorKind::WouldBlock のエラーを返す
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    // IPv6 のみを扱うかどうか
+    pub fn only_v6(&self) -> bool {
+        !self.link.supports(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    // :: にバインドしたソケットで IPv4 を扱うかどうかを切り替える
+    pub fn set_only_v6(&mut self, only_v6: bool) -> Result<()> {
+        match self.local_ip {
+            IpAddr::V6(ip) if ip.is_unspecified() => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only sockets bound to :: can change dual-stack mode",
+                )
+                .into())
+            }
+        }
+        self.link.set_v4(!only_v6)?;
+        // 開き直したチャネルも待てるようにする
+        for fd in self.link.fds() {
+            sys::epoll_add(self.poller.as_raw_fd(), fd)?;
+        }
+        Ok(())
+    }
+
+    // 送信するデータグラムの断片化を禁止する (IPv4 では DF ビットを立てる)
+    // 禁止すると経路MTUを超えるデータグラムの送信は EMSGSIZE で失敗する
+    pub fn set_dont_fragment(&mut self, dont_fragment: bool) -> Result<()> {
+        Ok(self.link.set_dont_fragment(dont_fragment)?)
+    }
+
+    // 宛先までの経路MTU (IP ヘッダを含む)
+    // ICMP の Fragmentation Needed / Packet Too Big を受信すると小さくなる
+    pub fn path_mtu<T: ToSocketAddrs>(&mut self, dest: T) -> Result<usize> {
+        let dest = resolve(dest)?;
+        if !self.link.supports(dest.ip()) {
+            return Err(Error::UnsupportedFamily(dest));
+        }
+        Ok(self.link.path_mtu(dest.ip())?)
+    }
+
+    // ユニキャストで送信するパケットの TTL (IPv6 では Hop Limit)。マルチキャストは set_multicast_ttl_v4 で設定する
+    pub fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        if ttl == 0 || ttl > u8::MAX as u32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TTL must be between 1 and 255",
+            )
+            .into());
+        }
+        self.link.set_ttl(ttl as u8)?;
+        self.ttl = ttl as u8;
+        Ok(())
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl as u32
+    }
+
+    // 送信するパケットの TOS (IPv6 では Traffic Class)。上位 6 ビットが DSCP、下位 2 ビットが ECN
+    pub fn set_tos(&mut self, tos: u8) -> Result<()> {
+        self.link.set_tos(tos)?;
+        self.tos = tos;
+        Ok(())
+    }
+
+    pub fn tos(&self) -> u8 {
+        self.tos
+    }
+
+    // TOS のうち DSCP だけを変える (0 から 63)
+    pub fn set_dscp(&mut self, dscp: u8) -> Result<()> {
+        if dscp > 63 {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "DSCP must be 63 or less").into(),
+            );
+        }
+        self.set_tos(tos::tos(dscp, self.ecn()))
+    }
+
+    pub fn dscp(&self) -> u8 {
+        tos::dscp(self.tos)
+    }
+
+    // TOS のうち ECN のコードポイントだけを変える
+    pub fn set_ecn(&mut self, ecn: Ecn) -> Result<()> {
+        self.set_tos(tos::tos(self.dscp(), ecn))
+    }
+
+    pub fn ecn(&self) -> Ecn {
+        Ecn::from_tos(self.tos)
+    }
+
+    // ブロードキャストアドレスへの送信を許可する
+    // 許可していない場合 255.255.255.255 やサブネットのブロードキャストアドレスへの送信は
+    // io::ErrorKind::PermissionDenied (EACCES) で失敗する
+    // 255.255.255.255 にはバインドしたアドレス (0.0.0.0 なら経路で選んだ送信元) のインターフェースから送信する
+    pub fn set_broadcast(&mut self, broadcast: bool) -> Result<()> {
+        self.link.set_broadcast(broadcast)?;
+        self.broadcast = broadcast;
+        Ok(())
+    }
+
+    pub fn broadcast(&self) -> bool {
+        self.broadcast
+    }
+
+    // IPv4 のマルチキャストグループに参加する
+    // interface は参加するインターフェースのアドレスで、0.0.0.0 なら経路で選ぶ
+    // ユーザ空間の経路では IGMP の Membership Report を送信し、Query にも応答する
+    pub fn join_multicast_v4(&mut self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> Result<()> {
+        check_multicast_v4(multiaddr)?;
+        Ok(self.link.join_multicast_v4(*multiaddr, *interface)?)
+    }
+
+    // 参加したグループから離脱する。引数は参加した時と同じものを指定する
+    pub fn leave_multicast_v4(&mut self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> Result<()> {
+        check_multicast_v4(multiaddr)?;
+        Ok(self.link.leave_multicast_v4(*multiaddr, *interface)?)
+    }
+
+    // 送信するマルチキャストの TTL (既定値は 1 で、ローカルネットワークの外には出ない)
+    pub fn set_multicast_ttl_v4(&mut self, ttl: u32) -> Result<()> {
+        if ttl > u8::MAX as u32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "multicast TTL must be 255 or less",
+            )
+            .into());
+        }
+        let ttl = ttl as u8;
+        self.link.set_multicast_ttl_v4(ttl)?;
+        self.multicast_ttl_v4 = ttl;
+        Ok(())
+    }
+
+    pub fn multicast_ttl_v4(&self) -> u32 {
+        self.multicast_ttl_v4 as u32
+    }
+
+    // 送信したマルチキャストを、グループに参加している自分にも配送するかどうか (既定値は有効)
+    pub fn set_multicast_loop_v4(&mut self, multicast_loop_v4: bool) -> Result<()> {
+        self.link.set_multicast_loop_v4(multicast_loop_v4)?;
+        self.multicast_loop_v4 = multicast_loop_v4;
+        Ok(())
+    }
+
+    pub fn multicast_loop_v4(&self) -> bool {
+        self.multicast_loop_v4
+    }
+
     // 指定した宛先にUDPデータを送信する
     pub fn send_to<T: ToSocketAddrs>(&mut self, payload: &[u8], dest: T) -> Result<usize> {
-        let total_length = UDP_HEADER_SIZE + payload.len();
-        let mut buffer = vec![0; total_length];
-        let mut packet = MutableUdpPacket::new(&mut buffer).context("failed to create packet")?;
-        let dest = match dest
-            .to_socket_addrs()?
-            .next()
-            .context("invalid destination")?
-        {
-            SocketAddr::V4(addr) => addr,
-            SocketAddr::V6(_) => anyhow::bail!("IPv6 address is not supported"),
+        check_payload(payload)?;
+        self.send_with(dest, |buffer| {
+            buffer[..payload.len()].copy_from_slice(payload);
+            payload.len()
+        })
+    }
+
+    // ペイロードをUDPパケットのバッファに直接書き込んで送信する
+    // fill は書き込める領域 (前に送信した内容が残っている) を受け取り、書き込んだ長さを返す
+    // バッファはソケット毎に使い回すので、送信の度にメモリを確保することはない
+    pub fn send_with<T, F>(&mut self, dest: T, fill: F) -> Result<usize>
+    where
+        T: ToSocketAddrs,
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let dest = resolve(dest)?;
+        if !self.link.supports(dest.ip()) {
+            return Err(Error::UnsupportedFamily(dest));
+        }
+        self.check_broadcast(dest)?;
+        // チェックサム計算に使う送信元アドレス
+        let source = self.source_addr_for(dest)?;
+        // 最初の送信で最大の長さまで確保しておく
+        self.send_buffer.resize(u16::MAX as usize, 0);
+        let len = fill(&mut self.send_buffer[UDP_HEADER_SIZE..]);
+        let total_length = UDP_HEADER_SIZE + len;
+        if total_length > u16::MAX as usize {
+            return Err(Error::PayloadTooLarge(len));
+        }
+        let packet = &mut self.send_buffer[..total_length];
+        write_header(packet, self.port, source, dest)?;
+        self.link
+            .send(packet, source, dest.ip())
+            .map_err(Error::Send)
+    }
+
+    pub fn recv_from(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let meta = self.recv_from_meta(buffer)?;
+        let n = if self.recv_trunc {
+            meta.original_len
+        } else {
+            meta.len
         };
-        // 送信元port番号
-        packet.set_source(self.port);
-        // 宛先ポート番号
-        packet.set_destination(dest.port());
-        // UDPデータグラムのペイロードを含めた全長。 単位はoctet
-        packet.set_length(total_length as u16);
-        // payroad
-        packet.set_payload(payload);
-        //check sum
-        packet.set_checksum(udp::ipv4_checksum(
-            &packet.to_immutable(),
-            &LOCAL_ADDR.parse::<Ipv4Addr>()?,
-            dest.ip(),
-        ));
-        self.sender
-            .send_to(packet, IpAddr::from(*dest.ip()))
-            .context("failed to send")
-    }
-
-    pub fn recv_from(&mut self, mut buffer: &mut [u8]) -> Result<(usize, SocketAddr)> {
-        let mut packet_iter = transport::udp_packet_iter(&mut self.receiver);
+        Ok((n, meta.source))
+    }
+
+    // データグラムを受信し、切り詰めの有無などの情報を返す
+    pub fn recv_from_meta(&mut self, buffer: &mut [u8]) -> Result<RecvMeta> {
+        // 無関係なパケットを読み飛ばしても全体でタイムアウトするように期限を決めておく
+        let (deadline, kind) = if self.nonblocking {
+            (Some(Instant::now()), io::ErrorKind::WouldBlock)
+        } else {
+            let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+            (deadline, io::ErrorKind::TimedOut)
+        };
+        self.recv_until(buffer, deadline, kind)
+    }
+
+    // recvmsg(2) のように、データグラムと一緒に宛先アドレスや到着したインターフェース、受信時刻を受け取る
+    // 返すものは recv_from_meta と同じ
+    pub fn recv_msg(&mut self, buffer: &mut [u8]) -> Result<RecvMeta> {
+        self.recv_from_meta(buffer)
+    }
+
+    // 期限までにデータグラムを1つ受信する。受信できなければ kind のエラーを返す
+    fn recv_until(
+        &mut self,
+        buffer: &mut [u8],
+        deadline: Option<Instant>,
+        kind: io::ErrorKind,
+    ) -> Result<RecvMeta> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(e.into());
+        }
         loop {
-            if let Ok((udp_packet, IpAddr::V4(src_addr))) = packet_iter.next() {
-                // ソケットに紐づくポート意外に到達したパケットは無視する
-                if self.port != udp_packet.get_destination() {
+            let datagram = match self.link.recv(deadline) {
+                Ok(Some(datagram)) => datagram,
+                Ok(None) => return Err(io::Error::from(kind).into()),
+                // 送信したデータグラムに ICMP エラーが返ってきた
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => return Err(e.into()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    self.drop_packet(DropReason::Malformed, false);
                     continue;
                 }
-                // チェックサムの検証
-                if udp_packet.get_checksum() != 0
-                    && udp_packet.get_checksum()
-                        != udp::ipv4_checksum(
-                            &udp_packet,
-                            &src_addr,
-                            &LOCAL_ADDR.parse::<Ipv4Addr>()?,
-                        )
-                {
+                // 割り込みなどの一時的なものだけ期限まで読み直し、それ以外はそのまま返す
+                Err(e) => {
+                    self.drop_packet(DropReason::ReadError, false);
+                    if !is_transient(&e) {
+                        return Err(e.into());
+                    }
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        return Err(io::Error::from(kind).into());
+                    }
                     continue;
                 }
-                let n = io::copy(&mut udp_packet.payload(), &mut buffer)? as usize;
-                // 読み込んだバイト数と送信元のソケットアドレスを返す
-                return Ok((
-                    n,
-                    SocketAddr::new(IpAddr::V4(src_addr), udp_packet.get_source()),
-                ));
+            };
+            match self.accept(&datagram, self.link.packet(), buffer) {
+                Ok(meta) => return Ok(meta),
+                Err(reason) => self.drop_packet(reason, true),
+            }
+        }
+    }
+
+    // 受信したデータグラムがこのソケット宛てか検証し、ペイロードをバッファにコピーする
+    fn accept(
+        &self,
+        datagram: &Datagram,
+        packet: &[u8],
+        buffer: &mut [u8],
+    ) -> std::result::Result<RecvMeta, DropReason> {
+        // バインドしたアドレス以外に届いたパケットは無視する
+        if !self.link.supports(datagram.destination)
+            || (!self.local_ip.is_unspecified() && self.local_ip != datagram.destination)
+        {
+            return Err(DropReason::WrongAddress);
+        }
+        let udp_packet = UdpPacket::new(packet).ok_or(DropReason::Malformed)?;
+        // ソケットに紐づくポート意外に到達したパケットは無視する
+        if self.port != udp_packet.get_destination() {
+            return Err(DropReason::WrongPort);
+        }
+        let source = SocketAddr::new(datagram.source, udp_packet.get_source());
+        // connect している場合は通信相手以外からのパケットは無視する
+        if self.peer.is_some_and(|peer| peer != source) {
+            return Err(DropReason::WrongPeer);
+        }
+        // チェックサムの検証
+        // 疑似ヘッダには実際に届いた宛先アドレスを使う
+        let valid = match (datagram.source, datagram.destination) {
+            (IpAddr::V4(source), IpAddr::V4(dest)) => {
+                udp_packet.get_checksum() == 0
+                    || udp_packet.get_checksum() == udp::ipv4_checksum(&udp_packet, &source, &dest)
+            }
+            (IpAddr::V6(source), IpAddr::V6(dest)) => {
+                udp_packet.get_checksum() == udp::ipv6_checksum(&udp_packet, &source, &dest)
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(DropReason::BadChecksum);
+        }
+        // バッファに収まらない部分は捨てる
+        let payload = udp_packet.payload();
+        let len = payload.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&payload[..len]);
+        Ok(RecvMeta {
+            len,
+            original_len: payload.len(),
+            truncated: len < payload.len(),
+            source,
+            ttl: datagram.ttl,
+            tos: datagram.tos,
+            destination: datagram.destination,
+            interface: datagram.interface,
+            timestamp: datagram.timestamp,
+        })
+    }
+
+    // 破棄したパケットを数え、設定されていれば関数を呼び出す
+    fn drop_packet(&mut self, reason: DropReason, received: bool) {
+        self.stats.record(reason);
+        if let Some(mut hook) = self.drop_hook.take() {
+            let packet = if received { self.link.packet() } else { &[] };
+            hook(packet, reason);
+            self.drop_hook = Some(hook);
+        }
+    }
+
+    // recv_batch で受信した index 番目のパケットを破棄する
+    fn drop_batch_packet(&mut self, reason: DropReason, index: usize) {
+        self.stats.record(reason);
+        if let Some(mut hook) = self.drop_hook.take() {
+            hook(self.link.batch_packet(index), reason);
+            self.drop_hook = Some(hook);
+        }
+    }
+
+    // イベントループに登録できる経路かどうか
+    #[cfg(any(feature = "tokio", feature = "mio"))]
+    fn check_pollable(&self) -> io::Result<()> {
+        if self.link.fds().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "backend has no file descriptors to wait on",
+            ));
+        }
+        Ok(())
+    }
+
+    // ブロードキャストを許可していなければ 255.255.255.255 への送信をカーネルと同じく EACCES で拒否する
+    // サブネットのブロードキャストアドレスは経路が検査する
+    fn check_broadcast(&self, dest: SocketAddr) -> Result<()> {
+        match dest.ip() {
+            IpAddr::V4(ip) if ip.is_broadcast() && !self.broadcast => {
+                Err(Error::Send(io::Error::from_raw_os_error(libc::EACCES)))
             }
+            _ => Ok(()),
+        }
+    }
+
+    // 宛先に対して使う送信元アドレスを決める
+    // 0.0.0.0 や :: にバインドしている場合は経路表から実際に使われるアドレスを求める
+    fn source_addr_for(&mut self, dest: SocketAddr) -> Result<IpAddr> {
+        // マルチキャストのグループのアドレスにバインドしたソケットは、そのアドレスからは送信できない
+        if !self.local_ip.is_unspecified() && !self.local_ip.is_multicast() {
+            return Ok(self.local_ip);
         }
+        if let Some(source) = self.link.source_for(dest.ip())? {
+            return Ok(source);
+        }
+        if let Some(source) = self.sources.get(dest.ip()) {
+            return Ok(source);
+        }
+        // connect したUDPソケットのローカルアドレスはカーネルが経路から選んだものになる
+        // (connect ではパケットは送信されない)
+        let probe = match dest {
+            SocketAddr::V4(_) => net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+            SocketAddr::V6(_) => net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+        };
+        // サブネットのブロードキャストアドレスに connect するには SO_BROADCAST が要る
+        if self.broadcast && dest.is_ipv4() {
+            probe.set_broadcast(true)?;
+        }
+        probe.connect(dest)?;
+        let source = probe.local_addr()?.ip();
+        self.sources.insert(dest.ip(), source);
+        Ok(source)
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) || e.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+fn check_multicast_v4(multiaddr: &Ipv4Addr) -> Result<()> {
+    if !multiaddr.is_multicast() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a multicast address", multiaddr),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// ペイロードを書き込んだバッファ (UDPパケットの長さちょうど) にヘッダを書き込む
+fn write_header(buffer: &mut [u8], port: u16, source: IpAddr, dest: SocketAddr) -> Result<()> {
+    let total_length = buffer.len();
+    let mut packet = MutableUdpPacket::new(buffer).ok_or(Error::BufferTooSmall)?;
+    // 送信元port番号
+    packet.set_source(port);
+    // 宛先ポート番号
+    packet.set_destination(dest.port());
+    // UDPデータグラムのペイロードを含めた全長。 単位はoctet
+    packet.set_length(total_length as u16);
+    //check sum
+    let checksum = match (source, dest.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(dest)) => {
+            udp::ipv4_checksum(&packet.to_immutable(), &source, &dest)
+        }
+        (IpAddr::V6(source), IpAddr::V6(dest)) => {
+            // IPv6 ではチェックサムは必須
+            udp::ipv6_checksum(&packet.to_immutable(), &source, &dest)
+        }
+        _ => return Err(Error::UnsupportedFamily(dest)),
+    };
+    packet.set_checksum(checksum);
+    Ok(())
+}
+
+// ペイロードが1つのデータグラムに収まるか確かめ、UDPパケットの長さを返す
+fn check_payload(payload: &[u8]) -> Result<usize> {
+    let total_length = UDP_HEADER_SIZE + payload.len();
+    if total_length > u16::MAX as usize {
+        return Err(Error::PayloadTooLarge(payload.len()));
+    }
+    Ok(total_length)
+}
+
+// アドレスを解決して最初のものを返す
+fn resolve<T: ToSocketAddrs>(addr: T) -> Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or(Error::InvalidAddress)
+}
+
+// 受信できるものが届くと読み込み可能になるディスクリプタ
+// (経路の raw チャネルや TUN デバイスなどをまとめた epoll インスタンス)
+// 読み込み可能になったら、ノンブロッキングモードの recv_from で WouldBlock になるまで受信する
+// SimNetwork のようにディスクリプタを持たない経路では読み込み可能にならない
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.poller.as_raw_fd()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.link.release_port(self.port);
     }
 }
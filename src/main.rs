@@ -1,9 +1,11 @@
 use anyhow::Result;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 use std::{env, io, str};
 use udp::UdpSocket;
 
 const BUFFER_SIZE: usize = 65535;
-const CLIENT_PORT: u16 = 15000;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
 
 /// # エコーサーバ・クライアント
 /// ## 実行例
@@ -27,8 +29,14 @@ fn main() -> Result<()> {
     }
 }
 
+// デュアルスタックの :: にバインドし、IPv6 が使えないホストでは 0.0.0.0 にバインドする
+fn bind(port: u16) -> udp::Result<UdpSocket> {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+        .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)))
+}
+
 fn echo_server(port: u16) -> Result<()> {
-    let mut socket = UdpSocket::new(port)?;
+    let mut socket = bind(port)?;
     loop {
         let mut buffer = [0; BUFFER_SIZE];
         let (size, src) = socket.recv_from(&mut buffer)?;
@@ -38,14 +46,24 @@ fn echo_server(port: u16) -> Result<()> {
 }
 
 fn echo_client(dest: &str) -> Result<()> {
-    let mut socket = UdpSocket::new(CLIENT_PORT)?;
+    let mut socket = bind(0)?;
+    // 応答が失われても待ち続けないようにする
+    socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    socket.connect(dest)?;
     loop {
         let mut input = String::new();
         io::stdin().read_line(&mut input)?;
-        socket.send_to(input.as_bytes(), dest)?;
+        socket.send(input.as_bytes())?;
 
         let mut buffer = [0; BUFFER_SIZE];
-        let (n, _) = socket.recv_from(&mut buffer)?;
+        let n = match socket.recv(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                eprintln!("no reply from {}", dest);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
         print!("{}", str::from_utf8(&buffer[..n])?)
     }
 }
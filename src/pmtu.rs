@@ -0,0 +1,45 @@
+// 宛先毎の経路MTU (PMTU) のキャッシュ
+// ICMP の Fragmentation Needed (IPv6 では Packet Too Big) で知った値を覚えておく
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// 経路が変わって大きくなっている可能性があるので、一定時間で忘れる (RFC 1191 の推奨値)
+const LIFETIME: Duration = Duration::from_secs(10 * 60);
+// IPv6 のリンクは必ずこの大きさを扱える
+const MIN_MTU_V6: usize = 1280;
+
+pub(crate) struct PmtuCache {
+    entries: HashMap<IpAddr, (usize, Instant)>,
+}
+
+impl PmtuCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, destination: IpAddr) -> Option<usize> {
+        let (mtu, expires_at) = *self.entries.get(&destination)?;
+        if expires_at <= Instant::now() {
+            self.entries.remove(&destination);
+            return None;
+        }
+        Some(mtu)
+    }
+
+    // 今の値より小さい場合だけ更新する
+    pub(crate) fn update(&mut self, destination: IpAddr, mtu: usize) {
+        let minimum = match destination {
+            IpAddr::V4(_) => crate::fragment::MIN_MTU,
+            IpAddr::V6(_) => MIN_MTU_V6,
+        };
+        let mtu = mtu.max(minimum);
+        if self.get(destination).is_some_and(|current| current <= mtu) {
+            return;
+        }
+        self.entries
+            .insert(destination, (mtu, Instant::now() + LIFETIME));
+    }
+}
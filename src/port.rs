@@ -0,0 +1,77 @@
+// このクレートで生成したソケットが使用中のポート番号を管理する
+use crate::{filter, sys};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{self, IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// IANA が動的・プライベートポートとして定めている範囲
+pub(crate) const EPHEMERAL_START: u16 = 49152;
+const EPHEMERAL_END: u16 = 65535;
+
+// 使用中のポートと、ホストのカーネルで同じポートを塞いでいるソケット
+static PORTS_IN_USE: Mutex<BTreeMap<u16, Option<net::UdpSocket>>> = Mutex::new(BTreeMap::new());
+
+// 指定したポートを使用中として登録する。0 の場合はエフェメラルポートから空きを割り当てる
+pub(crate) fn allocate(ip: IpAddr, port: u16) -> io::Result<u16> {
+    let mut ports = PORTS_IN_USE.lock().unwrap();
+    if port != 0 {
+        if ports.contains_key(&port) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("port {} is already in use", port),
+            ));
+        }
+        // カーネルのソケットが既に使っている場合も、そのソケットが応答するので塞がなくてよい
+        ports.insert(port, reserve(ip, port).unwrap_or(None));
+        return Ok(port);
+    }
+    // 複数のプロセスが同じ順序で探さないように開始位置をずらす
+    let range = (EPHEMERAL_END - EPHEMERAL_START) as u32 + 1;
+    let start = (seed() % range) as u16;
+    for i in 0..range as u16 {
+        let candidate = EPHEMERAL_START + (start + i) % range as u16;
+        if ports.contains_key(&candidate) {
+            continue;
+        }
+        // カーネルのUDPソケットが既に使っているポートは避ける
+        if let Ok(reservation) = reserve(ip, candidate) {
+            ports.insert(candidate, reservation);
+            return Ok(candidate);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        "no ephemeral port available",
+    ))
+}
+
+// ソケットの破棄時にポートを解放する
+pub(crate) fn release(port: u16) {
+    PORTS_IN_USE.lock().unwrap().remove(&port);
+}
+
+// ホストのカーネルにもソケットをバインドしておく
+// raw ソケットだけが使っているポートには、カーネルが届いたデータグラム毎に Port Unreachable を返してしまう
+// 全て捨てるフィルタを付けるので、このソケットの受信バッファは溜まらない
+// ホストに無いアドレス (TUN の先のアドレスなど) はカーネルと競合しないので塞がない
+fn reserve(ip: IpAddr, port: u16) -> io::Result<Option<net::UdpSocket>> {
+    let socket = match net::UdpSocket::bind(SocketAddr::new(ip, port)) {
+        Ok(socket) => socket,
+        Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    sys::attach_filter(socket.as_raw_fd(), &filter::reject_all())?;
+    Ok(Some(socket))
+}
+
+fn seed() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    nanos ^ process::id().rotate_left(16)
+}
@@ -0,0 +1,636 @@
+// pnet の raw チャネルでUDPパケットを送受信する
+use crate::backend::{Backend, Datagram};
+use crate::icmp::{self, Listener};
+use crate::sys::{self, Ancillary};
+use crate::{filter, ipv4, port, Error, Result};
+use pnet::datalink;
+use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, udp::UdpPacket};
+use pnet::transport::{
+    self, TransportChannelType, TransportProtocol, TransportReceiver, TransportSender,
+};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::time::Instant;
+
+const BUFFER_SIZE: usize = 65535;
+
+// アドレスファミリ毎の raw チャネル
+pub(crate) struct Channel {
+    pub(crate) sender: TransportSender,
+    pub(crate) receiver: TransportReceiver,
+}
+
+impl Channel {
+    fn open(protocol: TransportProtocol) -> Result<Self> {
+        // channel の生成
+        let (sender, receiver) =
+            transport::transport_channel(BUFFER_SIZE, TransportChannelType::Layer4(protocol))
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::PermissionDenied => Error::PermissionDenied(e),
+                    _ => Error::Io(e),
+                })?;
+        match protocol {
+            TransportProtocol::Ipv4(IpNextHeaderProtocols::Udp) => {
+                sys::set_recv_ancillary_v4(receiver.socket.fd)?
+            }
+            TransportProtocol::Ipv6(IpNextHeaderProtocols::Udp) => {
+                sys::set_recv_ancillary_v6(receiver.socket.fd)?
+            }
+            _ => {}
+        }
+        Ok(Self { sender, receiver })
+    }
+
+    pub(crate) fn v4() -> Result<Self> {
+        Self::open(TransportProtocol::Ipv4(IpNextHeaderProtocols::Udp))
+    }
+
+    pub(crate) fn v6() -> Result<Self> {
+        Self::open(TransportProtocol::Ipv6(IpNextHeaderProtocols::Udp))
+    }
+
+    pub(crate) fn icmp_v4() -> Result<Self> {
+        Self::open(TransportProtocol::Ipv4(IpNextHeaderProtocols::Icmp))
+    }
+
+    pub(crate) fn icmp_v6() -> Result<Self> {
+        Self::open(TransportProtocol::Ipv6(IpNextHeaderProtocols::Icmpv6))
+    }
+}
+
+// UDPパケットを送信する
+pub(crate) fn send(sender: &mut TransportSender, packet: &[u8], dest: IpAddr) -> io::Result<usize> {
+    let packet = UdpPacket::new(packet)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid UDP packet"))?;
+    sender.send_to(packet, dest)
+}
+
+// データグラムを1つ受信バッファに読み込む
+// UDPパケットは受信バッファの返した範囲に入っている
+pub(crate) fn recv(
+    receiver: &mut TransportReceiver,
+    flags: libc::c_int,
+) -> io::Result<(Datagram, Range<usize>)> {
+    match receiver.channel_type {
+        TransportChannelType::Layer4(TransportProtocol::Ipv6(_)) => recv_v6(receiver, flags),
+        _ => recv_v4(receiver, flags),
+    }
+}
+
+// アドレスを持つインターフェースのインデックス
+fn interface_index(ip: Ipv4Addr) -> Option<u32> {
+    datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.ips.iter().any(|network| network.ip() == ip))
+        .map(|interface| interface.index)
+}
+
+// ソケット毎に pnet の raw チャネルを開いて送受信する
+pub struct RawBackend {
+    v4: Option<Channel>,
+    v6: Option<Channel>,
+    // ICMP エラーを受信するチャネル (送信には使わない)
+    icmp_v4: Option<TransportReceiver>,
+    icmp_v6: Option<TransportReceiver>,
+    errors: Listener,
+    // None の場合はカーネルの既定値のまま
+    dont_fragment: Option<bool>,
+    // カーネルで絞り込むポートと通信相手
+    filter: Option<(u16, Option<SocketAddr>)>,
+    // 参加しているマルチキャストグループとインターフェース
+    memberships: Vec<(Ipv4Addr, Ipv4Addr)>,
+    // None の場合はカーネルの既定値のまま (TTL は 1、ループバックは有効)
+    multicast_ttl: Option<u8>,
+    multicast_loop: Option<bool>,
+    // None の場合はカーネルの既定値 (無効) のまま
+    broadcast: Option<bool>,
+    // None の場合はカーネルの既定値のまま
+    ttl: Option<u8>,
+    tos: Option<u8>,
+    // マルチキャストの送信元として IP_MULTICAST_IF に設定したアドレス
+    multicast_if: Option<Ipv4Addr>,
+    // 255.255.255.255 の送信元として IP_UNICAST_IF でインターフェースを選んだアドレス
+    unicast_if: Option<Ipv4Addr>,
+    // 最後に受信したチャネルと受信バッファ中のUDPパケットの範囲
+    last: Option<(IpAddr, Range<usize>)>,
+    // recv_batch の受信バッファ (データグラム毎に1つ) と、最後に受信したUDPパケットの範囲
+    batch: Vec<Vec<u8>>,
+    batch_ranges: Vec<Range<usize>>,
+}
+
+impl RawBackend {
+    // 指定したアドレスファミリの raw チャネルを開く
+    pub fn new(v4: bool, v6: bool) -> Result<Self> {
+        Ok(Self {
+            v4: if v4 { Some(Channel::v4()?) } else { None },
+            v6: if v6 { Some(Channel::v6()?) } else { None },
+            icmp_v4: if v4 {
+                Some(Channel::icmp_v4()?.receiver)
+            } else {
+                None
+            },
+            icmp_v6: if v6 {
+                Some(Channel::icmp_v6()?.receiver)
+            } else {
+                None
+            },
+            errors: Listener::new(),
+            dont_fragment: None,
+            filter: None,
+            memberships: Vec::new(),
+            multicast_ttl: None,
+            multicast_loop: None,
+            broadcast: None,
+            ttl: None,
+            tos: None,
+            multicast_if: None,
+            unicast_if: None,
+            last: None,
+            batch: Vec::new(),
+            batch_ranges: Vec::new(),
+        })
+    }
+
+    fn v4_fd(&self) -> io::Result<RawFd> {
+        self.v4
+            .as_ref()
+            .map(|channel| channel.receiver.socket.fd)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    // マルチキャストと 255.255.255.255 はカーネルがインターフェースを選んで送信元を決めるので、
+    // チェックサムの計算に使った送信元のインターフェースから送信させる
+    fn select_interface(&mut self, source: IpAddr, destination: IpAddr) -> io::Result<()> {
+        if self.interface_selected(source, destination) {
+            return Ok(());
+        }
+        let (source, destination) = match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => (source, destination),
+            _ => return Ok(()),
+        };
+        let fd = self.v4_fd()?;
+        if destination.is_multicast() {
+            sys::set_multicast_if_v4(fd, source)?;
+            self.multicast_if = Some(source);
+        } else {
+            // ユニキャストに戻す時は経路で選ばせる
+            let source = Some(source).filter(|_| destination.is_broadcast());
+            sys::set_unicast_if_v4(fd, source.and_then(interface_index).unwrap_or(0))?;
+            self.unicast_if = source;
+        }
+        Ok(())
+    }
+
+    // 送信元から宛先に送るインターフェースを選んであるかどうか
+    fn interface_selected(&self, source: IpAddr, destination: IpAddr) -> bool {
+        match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) if destination.is_multicast() => {
+                self.multicast_if == Some(source)
+            }
+            (IpAddr::V4(source), IpAddr::V4(destination)) if destination.is_broadcast() => {
+                self.unicast_if == Some(source)
+            }
+            (_, IpAddr::V4(_)) => self.unicast_if.is_none(),
+            (_, IpAddr::V6(_)) => true,
+        }
+    }
+
+    // ICMP エラーを1つ受信して反映する
+    fn recv_icmp(&mut self, v6: bool, flags: libc::c_int) -> io::Result<()> {
+        let receiver = if v6 {
+            self.icmp_v6.as_mut()
+        } else {
+            self.icmp_v4.as_mut()
+        };
+        let receiver = match receiver {
+            Some(receiver) => receiver,
+            None => return Ok(()),
+        };
+        let len = sys::recv(receiver.socket.fd, &mut receiver.buffer, flags)?;
+        let packet = &receiver.buffer[..len];
+        let report = if v6 {
+            icmp::parse_v6(packet)
+        } else {
+            icmp::parse_v4(packet)
+        };
+        if let Some(report) = report {
+            self.errors.handle(&report);
+        }
+        Ok(())
+    }
+
+    // 待たずに受信する (ノンブロッキングや recv_batch の2つ目以降)
+    // poll を省いて各チャネルを順に読んでみるので、システムコールが1回で済むことが多い
+    fn try_recv(&mut self) -> io::Result<Option<Datagram>> {
+        self.last = None;
+        for channel in self.v4.iter_mut().chain(self.v6.iter_mut()) {
+            match recv(&mut channel.receiver, libc::MSG_DONTWAIT) {
+                Ok((datagram, range)) => {
+                    self.last = Some((datagram.source, range));
+                    return Ok(Some(datagram));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.drain_icmp()?;
+        self.errors.take_error()?;
+        Ok(None)
+    }
+
+    // 既に届いている ICMP エラーを全て処理する
+    // 送信の度に呼ばれるので、poll を使わずに待たずに読んでみる (メモリも確保しない)
+    fn drain_icmp(&mut self) -> io::Result<()> {
+        let icmp = [
+            (false, self.icmp_v4.is_some()),
+            (true, self.icmp_v6.is_some()),
+        ];
+        for &(v6, _) in icmp.iter().filter(|(_, open)| *open) {
+            loop {
+                match self.recv_icmp(v6, libc::MSG_DONTWAIT) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for RawBackend {
+    fn supports(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => self.v4.is_some(),
+            IpAddr::V6(_) => self.v6.is_some(),
+        }
+    }
+
+    // IPv4 のチャネルを開く、または閉じる
+    fn set_v4(&mut self, enabled: bool) -> io::Result<()> {
+        if !enabled {
+            self.v4 = None;
+            self.icmp_v4 = None;
+        } else if self.v4.is_none() {
+            let channel = Channel::v4()?;
+            if let Some(enabled) = self.dont_fragment {
+                sys::set_dont_fragment(channel.receiver.socket.fd, false, enabled)?;
+            }
+            if let Some((port, peer)) = self.filter {
+                sys::attach_filter(channel.receiver.socket.fd, &filter::v4(port, peer))?;
+            }
+            if let Some(ttl) = self.multicast_ttl {
+                sys::set_multicast_ttl_v4(channel.receiver.socket.fd, ttl)?;
+            }
+            if let Some(enabled) = self.multicast_loop {
+                sys::set_multicast_loop_v4(channel.receiver.socket.fd, enabled)?;
+            }
+            for &(group, interface) in &self.memberships {
+                sys::set_membership_v4(channel.receiver.socket.fd, group, interface, true)?;
+            }
+            if let Some(enabled) = self.broadcast {
+                sys::set_broadcast(channel.receiver.socket.fd, enabled)?;
+            }
+            if let Some(ttl) = self.ttl {
+                sys::set_ttl(channel.receiver.socket.fd, false, ttl)?;
+            }
+            if let Some(tos) = self.tos {
+                sys::set_tos(channel.receiver.socket.fd, false, tos)?;
+            }
+            self.multicast_if = None;
+            self.unicast_if = None;
+            self.icmp_v4 = Some(Channel::icmp_v4()?.receiver);
+            self.v4 = Some(channel);
+        }
+        Ok(())
+    }
+
+    // ホストに届く全てのUDPパケットで起こされないように、カーネルで絞り込む
+    fn set_filter(&mut self, port: u16, peer: Option<SocketAddr>) -> io::Result<()> {
+        if let Some(channel) = &self.v4 {
+            sys::attach_filter(channel.receiver.socket.fd, &filter::v4(port, peer))?;
+        }
+        if let Some(channel) = &self.v6 {
+            sys::attach_filter(channel.receiver.socket.fd, &filter::v6(port, peer))?;
+        }
+        self.filter = Some((port, peer));
+        Ok(())
+    }
+
+    // 送信と受信のチャネルは同じソケットを共有している
+    fn set_dont_fragment(&mut self, enabled: bool) -> io::Result<()> {
+        if let Some(channel) = &self.v4 {
+            sys::set_dont_fragment(channel.receiver.socket.fd, false, enabled)?;
+        }
+        if let Some(channel) = &self.v6 {
+            sys::set_dont_fragment(channel.receiver.socket.fd, true, enabled)?;
+        }
+        self.dont_fragment = Some(enabled);
+        Ok(())
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        if let Some(channel) = &self.v4 {
+            sys::set_ttl(channel.receiver.socket.fd, false, ttl)?;
+        }
+        if let Some(channel) = &self.v6 {
+            sys::set_ttl(channel.receiver.socket.fd, true, ttl)?;
+        }
+        self.ttl = Some(ttl);
+        Ok(())
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        if let Some(channel) = &self.v4 {
+            sys::set_tos(channel.receiver.socket.fd, false, tos)?;
+        }
+        if let Some(channel) = &self.v6 {
+            sys::set_tos(channel.receiver.socket.fd, true, tos)?;
+        }
+        self.tos = Some(tos);
+        Ok(())
+    }
+
+    // サブネットのブロードキャストアドレスへの送信もカーネルが EACCES で拒否する
+    fn set_broadcast(&mut self, enabled: bool) -> io::Result<()> {
+        sys::set_broadcast(self.v4_fd()?, enabled)?;
+        self.broadcast = Some(enabled);
+        Ok(())
+    }
+
+    fn join_multicast_v4(&mut self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        sys::set_membership_v4(self.v4_fd()?, group, interface, true)?;
+        self.memberships.push((group, interface));
+        Ok(())
+    }
+
+    fn leave_multicast_v4(&mut self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        sys::set_membership_v4(self.v4_fd()?, group, interface, false)?;
+        self.memberships
+            .retain(|&membership| membership != (group, interface));
+        Ok(())
+    }
+
+    fn set_multicast_ttl_v4(&mut self, ttl: u8) -> io::Result<()> {
+        sys::set_multicast_ttl_v4(self.v4_fd()?, ttl)?;
+        self.multicast_ttl = Some(ttl);
+        Ok(())
+    }
+
+    fn set_multicast_loop_v4(&mut self, enabled: bool) -> io::Result<()> {
+        sys::set_multicast_loop_v4(self.v4_fd()?, enabled)?;
+        self.multicast_loop = Some(enabled);
+        Ok(())
+    }
+
+    // カーネルの経路キャッシュの値と ICMP で知った値の小さい方
+    fn path_mtu(&mut self, destination: IpAddr) -> io::Result<usize> {
+        if !self.supports(destination) {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        self.drain_icmp()?;
+        let mtu = sys::path_mtu(destination)?;
+        Ok(self
+            .errors
+            .pmtu
+            .get(destination)
+            .map_or(mtu, |pmtu| pmtu.min(mtu)))
+    }
+
+    // 前の送信に対する ICMP エラーが届いていれば送信せずにエラーを返す
+    fn send(&mut self, packet: &[u8], source: IpAddr, destination: IpAddr) -> io::Result<usize> {
+        self.drain_icmp()?;
+        self.errors.take_error()?;
+        self.select_interface(source, destination)?;
+        let channel = match destination {
+            IpAddr::V4(_) => self.v4.as_mut(),
+            IpAddr::V6(_) => self.v6.as_mut(),
+        };
+        let channel = channel.ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+        send(&mut channel.sender, packet, destination)
+    }
+
+    // 同じアドレスファミリが続く間は sendmmsg でまとめて送る
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr, IpAddr)]) -> io::Result<usize> {
+        self.drain_icmp()?;
+        self.errors.take_error()?;
+        let mut sent = 0;
+        while sent < packets.len() {
+            let (_, source, destination) = packets[sent];
+            if let Err(e) = self.select_interface(source, destination) {
+                if sent == 0 {
+                    return Err(e);
+                }
+                break;
+            }
+            let v6 = destination.is_ipv6();
+            let channel = if v6 {
+                self.v6.as_ref()
+            } else {
+                self.v4.as_ref()
+            };
+            let channel = channel.ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+            let run = packets[sent..]
+                .iter()
+                .take_while(|&&(_, source, destination)| {
+                    destination.is_ipv6() == v6
+                        // 別のインターフェースから送るものは次の sendmmsg で送る
+                        && self.interface_selected(source, destination)
+                })
+                .map(|&(packet, _, destination)| (packet, destination))
+                .collect::<Vec<_>>();
+            match sys::sendmmsg(channel.sender.socket.fd, &run) {
+                Ok(0) => break,
+                Ok(n) => sent += n,
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(sent)
+    }
+
+    // 読み込み可能なチャネルからデータグラムを1つ受信する
+    // ICMP エラーは経路MTUの更新と Port Unreachable の通知に使う
+    fn recv(&mut self, deadline: Option<Instant>) -> io::Result<Option<Datagram>> {
+        if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+            return self.try_recv();
+        }
+        loop {
+            let fds = [
+                self.v4.as_ref().map(|channel| channel.receiver.socket.fd),
+                self.v6.as_ref().map(|channel| channel.receiver.socket.fd),
+                self.icmp_v4.as_ref().map(|receiver| receiver.socket.fd),
+                self.icmp_v6.as_ref().map(|receiver| receiver.socket.fd),
+            ];
+            // 開いているチャネルの fds 中の位置
+            let open = (0..fds.len())
+                .filter(|&i| fds[i].is_some())
+                .collect::<Vec<_>>();
+            let readable = sys::poll_readable(
+                &open.iter().filter_map(|&i| fds[i]).collect::<Vec<_>>(),
+                deadline,
+            )?;
+            let channel = match readable.map(|i| open[i]) {
+                Some(0) => self.v4.as_mut(),
+                Some(1) => self.v6.as_mut(),
+                Some(i) => {
+                    self.recv_icmp(i == 3, 0)?;
+                    self.errors.take_error()?;
+                    continue;
+                }
+                None => return Ok(None),
+            };
+            self.last = None;
+            let (datagram, range) = recv(&mut channel.unwrap().receiver, 0)?;
+            self.last = Some((datagram.source, range));
+            return Ok(Some(datagram));
+        }
+    }
+
+    // 各チャネルから recvmmsg でまとめて受信する
+    fn recv_batch(&mut self, max: usize) -> io::Result<Vec<Option<Datagram>>> {
+        self.batch_ranges.clear();
+        if self.batch.len() < max {
+            self.batch.resize_with(max, || vec![0; BUFFER_SIZE]);
+        }
+        let mut datagrams = Vec::new();
+        for &v6 in &[false, true] {
+            let channel = if v6 {
+                self.v6.as_ref()
+            } else {
+                self.v4.as_ref()
+            };
+            let start = datagrams.len();
+            let fd = match channel {
+                Some(channel) if start < max => channel.receiver.socket.fd,
+                _ => continue,
+            };
+            let received = match sys::recvmmsg(fd, &mut self.batch[start..max], v6) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) if start == 0 => return Err(e),
+                // 受信したデータグラムを先に返す
+                Err(_) => break,
+            };
+            for (i, (len, source, ancillary)) in received.into_iter().enumerate() {
+                let parsed = match source {
+                    Some(source) => parse_v6(source, ancillary).map(|datagram| (datagram, 0..len)),
+                    None => parse_v4(&self.batch[start + i][..len], ancillary),
+                };
+                let (datagram, range) = match parsed {
+                    Ok((datagram, range)) => (Some(datagram), range),
+                    Err(_) => (None, 0..0),
+                };
+                datagrams.push(datagram);
+                self.batch_ranges.push(range);
+            }
+        }
+        if datagrams.is_empty() {
+            self.drain_icmp()?;
+            self.errors.take_error()?;
+        }
+        Ok(datagrams)
+    }
+
+    fn bind_port(&mut self, ip: IpAddr, port: u16) -> io::Result<u16> {
+        let port = port::allocate(ip, port)?;
+        self.errors.set_port(port);
+        Ok(port)
+    }
+
+    fn set_peer(&mut self, peer: SocketAddr) -> io::Result<()> {
+        self.errors.set_peer(peer);
+        Ok(())
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        self.v4
+            .iter()
+            .chain(self.v6.iter())
+            .map(|channel| &channel.receiver)
+            .chain(self.icmp_v4.iter())
+            .chain(self.icmp_v6.iter())
+            .map(|receiver| receiver.socket.fd)
+            .collect()
+    }
+
+    fn packet(&self) -> &[u8] {
+        let (source, range) = match &self.last {
+            Some(last) => last,
+            None => return &[],
+        };
+        let channel = match source {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        };
+        channel.map_or(&[], |channel| &channel.receiver.buffer[range.clone()])
+    }
+
+    fn batch_packet(&self, index: usize) -> &[u8] {
+        match self.batch_ranges.get(index) {
+            Some(range) => &self.batch[index][range.clone()],
+            None => &[],
+        }
+    }
+}
+
+// IPv4 ではヘッダごと受信できるので、そこから宛先アドレスを得る
+// (Layer4 の受信イテレータはIPヘッダを読み飛ばしてしまうため直接読む)
+fn recv_v4(
+    receiver: &mut TransportReceiver,
+    flags: libc::c_int,
+) -> io::Result<(Datagram, Range<usize>)> {
+    let (len, ancillary) = sys::recv_v4(receiver.socket.fd, &mut receiver.buffer, flags)?;
+    parse_v4(&receiver.buffer[..len], ancillary)
+}
+
+// 受信した IPv4 パケットからデータグラムのアドレスとUDPパケットの範囲を得る
+fn parse_v4(packet: &[u8], ancillary: Ancillary) -> io::Result<(Datagram, Range<usize>)> {
+    let len = packet.len();
+    let ip_packet = Ipv4Packet::new(packet)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated IPv4 header"))?;
+    let offset = ip_packet.get_header_length() as usize * 4;
+    let end = (ip_packet.get_total_length() as usize).min(len);
+    if offset > end {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid IPv4 header",
+        ));
+    }
+    let mut datagram = Datagram::new(
+        IpAddr::V4(ip_packet.get_source()),
+        IpAddr::V4(ip_packet.get_destination()),
+    );
+    datagram.ttl = Some(ip_packet.get_ttl());
+    datagram.tos = Some(ipv4::tos(&ip_packet));
+    datagram.interface = ancillary.interface;
+    datagram.timestamp = ancillary.timestamp;
+    Ok((datagram, offset..end))
+}
+
+// IPv6 では宛先アドレスを補助データから得る
+fn recv_v6(
+    receiver: &mut TransportReceiver,
+    flags: libc::c_int,
+) -> io::Result<(Datagram, Range<usize>)> {
+    let (len, source, ancillary) = sys::recv_v6(receiver.socket.fd, &mut receiver.buffer, flags)?;
+    Ok((parse_v6(source, ancillary)?, 0..len))
+}
+
+// 補助データから IPv6 のデータグラムのアドレスを得る
+fn parse_v6(source: Ipv6Addr, ancillary: Ancillary) -> io::Result<Datagram> {
+    let destination = ancillary.destination.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing IPv6 destination address",
+        )
+    })?;
+    let mut datagram = Datagram::new(IpAddr::V6(source), IpAddr::V6(destination));
+    datagram.ttl = ancillary.hop_limit;
+    datagram.tos = ancillary.traffic_class;
+    datagram.interface = ancillary.interface;
+    datagram.timestamp = ancillary.timestamp;
+    Ok(datagram)
+}
@@ -0,0 +1,44 @@
+// 宛先毎に経路表で選んだ送信元アドレスのキャッシュ
+// 0.0.0.0 や :: にバインドしたソケットが送信の度にカーネルに問い合わせないようにする
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// 経路やアドレスの変更に追従できるように、短い時間で忘れる
+const LIFETIME: Duration = Duration::from_secs(1);
+// 多数の宛先に送るソケットでも大きくなり過ぎないようにする
+const MAX_ENTRIES: usize = 1024;
+
+pub(crate) struct SourceCache {
+    entries: HashMap<IpAddr, (IpAddr, Instant)>,
+}
+
+impl SourceCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, destination: IpAddr) -> Option<IpAddr> {
+        let (source, expires_at) = *self.entries.get(&destination)?;
+        if expires_at <= Instant::now() {
+            self.entries.remove(&destination);
+            return None;
+        }
+        Some(source)
+    }
+
+    // 一杯になったら期限切れのものを捨て、それでも一杯なら全て忘れる
+    pub(crate) fn insert(&mut self, destination: IpAddr, source: IpAddr) {
+        let now = Instant::now();
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries
+                .retain(|_, &mut (_, expires_at)| expires_at > now);
+            if self.entries.len() >= MAX_ENTRIES {
+                self.entries.clear();
+            }
+        }
+        self.entries.insert(destination, (source, now + LIFETIME));
+    }
+}
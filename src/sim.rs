@@ -0,0 +1,786 @@
+// プロセス内のスイッチでソケット同士をつなぐ模擬ネットワーク
+// raw ソケットの権限や実際のネットワーク無しにプロトコルの動作を確かめられる
+//
+// 時刻は仮想時計で管理する。ネットワークを生成したスレッドと spawn で起動したスレッドを
+// 1つずつ決まった順序で実行し、全てのスレッドが受信を待つと次のパケットが届く時刻
+// (またはタイムアウトの時刻) まで時計を進めるので、乱数のシードが同じなら結果も同じになる
+use crate::backend::{Backend, Datagram};
+use crate::port::EPHEMERAL_START;
+use crate::{resolve, Result, UdpSocket};
+use std::cell::Cell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+thread_local! {
+    // このスレッドが実行しているタスク (ネットワークの識別子とタスクの番号)
+    // spawn で起動していないスレッドはネットワークを生成したスレッド (0 番) として扱う
+    static TASK: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+// ホスト間の片方向のリンクの特性
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    // パケットを失う確率 (0.0 - 1.0)
+    pub loss: f64,
+    // パケットを複製する確率
+    pub duplicate: f64,
+    // 遅延させずに先に届けて順序を入れ替える確率 (latency が 0 の場合は効果が無い)
+    pub reorder: f64,
+    // 伝搬遅延
+    pub latency: Duration,
+    // 遅延に加える揺らぎの最大値
+    pub jitter: Duration,
+    // 帯域 (バイト/秒)。None の場合は無制限
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+        }
+    }
+}
+
+impl LinkConfig {
+    // 帯域が 0 ではパケットを送り出せない
+    fn validate(&self) -> io::Result<()> {
+        if self.bandwidth == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bandwidth must be greater than zero",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// 再現性のある乱数 (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // 状態が 0 だと常に 0 を返してしまう
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15 | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // 0.0 以上 1.0 未満の一様乱数
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    fn duration_up_to(&mut self, max: Duration) -> Duration {
+        max.mul_f64(self.next_f64())
+    }
+}
+
+// シミュレーション上のスレッドの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Task {
+    // 実行できる (実行中を含む)
+    Ready,
+    // ソケットに届くのを期限まで待っている
+    Receiving(SocketAddr, Option<Duration>),
+    // 他のタスクの終了を待っている
+    Joining(usize),
+    Finished,
+}
+
+// 転送中のパケット
+struct InFlight {
+    deliver_at: Duration,
+    // 同じ時刻に届くパケットは送信順に並べる
+    seq: u64,
+    source: IpAddr,
+    destination: SocketAddr,
+    packet: Vec<u8>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.seq).cmp(&(other.deliver_at, other.seq))
+    }
+}
+
+struct State {
+    rng: Rng,
+    // 仮想時計の現在時刻 (ネットワーク生成時からの経過時間)
+    now: Duration,
+    seq: u64,
+    default_link: LinkConfig,
+    links: HashMap<(IpAddr, IpAddr), LinkConfig>,
+    // リンク毎に送信が終わる時刻 (帯域制限用)
+    busy_until: HashMap<(IpAddr, IpAddr), Duration>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    // バインドされているソケット毎の受信キュー
+    sockets: HashMap<SocketAddr, VecDeque<(IpAddr, Vec<u8>)>>,
+    tasks: Vec<Task>,
+    // 実行中のタスク。None の場合は全てのタスクが待ち続けている
+    running: Option<usize>,
+}
+
+impl State {
+    // 現在時刻までに届くパケットを受信キューに移す
+    // バインドされていないポート宛てのものは捨てる
+    fn deliver_due(&mut self) {
+        while let Some(Reverse(next)) = self.in_flight.peek() {
+            if next.deliver_at > self.now {
+                break;
+            }
+            let Reverse(packet) = self.in_flight.pop().unwrap();
+            if let Some(queue) = self.sockets.get_mut(&packet.destination) {
+                queue.push_back((packet.source, packet.packet));
+            }
+        }
+    }
+
+    fn next_delivery(&self) -> Option<Duration> {
+        self.in_flight.peek().map(|Reverse(next)| next.deliver_at)
+    }
+
+    fn is_ready(&self, task: usize) -> bool {
+        match self.tasks[task] {
+            Task::Ready => true,
+            Task::Receiving(local, deadline) => {
+                self.sockets
+                    .get(&local)
+                    .is_some_and(|queue| !queue.is_empty())
+                    || deadline.is_some_and(|deadline| deadline <= self.now)
+            }
+            Task::Joining(other) => self.tasks[other] == Task::Finished,
+            Task::Finished => false,
+        }
+    }
+
+    // 次に実行するタスクを番号の小さい順に選ぶ
+    // 実行できるタスクが無ければ、次にパケットが届くかタイムアウトする時刻まで時計を進める
+    fn schedule(&mut self) {
+        loop {
+            self.running = (0..self.tasks.len()).find(|&task| self.is_ready(task));
+            if self.running.is_some() {
+                return;
+            }
+            let timeout = self
+                .tasks
+                .iter()
+                .filter_map(|task| match task {
+                    Task::Receiving(_, deadline) => *deadline,
+                    _ => None,
+                })
+                .min();
+            let next = match (self.next_delivery(), timeout) {
+                (Some(delivery), Some(timeout)) => delivery.min(timeout),
+                (Some(next), None) | (None, Some(next)) => next,
+                (None, None) => return,
+            };
+            self.now = self.now.max(next);
+            self.deliver_due();
+        }
+    }
+
+    fn transmit(&mut self, source: IpAddr, destination: SocketAddr, packet: &[u8]) {
+        let key = (source, destination.ip());
+        let link = *self.links.get(&key).unwrap_or(&self.default_link);
+        if self.rng.chance(link.loss) {
+            return;
+        }
+        // 帯域制限がある場合は前のパケットの送信が終わってから送り出す
+        let start = self
+            .busy_until
+            .get(&key)
+            .copied()
+            .unwrap_or_default()
+            .max(self.now);
+        let transmission = link.bandwidth.map_or(Duration::ZERO, |bandwidth| {
+            Duration::from_secs_f64(packet.len() as f64 / bandwidth as f64)
+        });
+        self.busy_until.insert(key, start + transmission);
+        let copies = if self.rng.chance(link.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let delay = if self.rng.chance(link.reorder) {
+                Duration::ZERO
+            } else {
+                link.latency + self.rng.duration_up_to(link.jitter)
+            };
+            self.seq += 1;
+            self.in_flight.push(Reverse(InFlight {
+                deliver_at: start + transmission + delay,
+                seq: self.seq,
+                source,
+                destination,
+                packet: packet.to_vec(),
+            }));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<State>>,
+    // 実行するタスクが変わったことを知らせる
+    turn: Arc<Condvar>,
+}
+
+impl SimNetwork {
+    // 乱数のシードを指定してネットワークを生成する
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                rng: Rng::new(seed),
+                now: Duration::ZERO,
+                seq: 0,
+                default_link: LinkConfig::default(),
+                links: HashMap::new(),
+                busy_until: HashMap::new(),
+                in_flight: BinaryHeap::new(),
+                sockets: HashMap::new(),
+                tasks: vec![Task::Ready],
+                running: Some(0),
+            })),
+            turn: Arc::new(Condvar::new()),
+        }
+    }
+
+    // シミュレーション上でスレッドを起動する
+    // 起動したスレッドは、実行中のスレッドが受信を待つか終了するまで実行されない
+    pub fn spawn<F, T>(&self, f: F) -> SimThread<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let task = {
+            let mut state = self.lock();
+            state.tasks.push(Task::Ready);
+            state.tasks.len() - 1
+        };
+        let network = self.clone();
+        let handle = thread::spawn(move || {
+            TASK.with(|current| current.set(Some((network.id(), task))));
+            let state = network.lock();
+            drop(network.wait_turn(state, task));
+            // パニックした場合も終了したことにして、他のタスクを止めない
+            let _finish = Finish {
+                network: &network,
+                task,
+            };
+            f()
+        });
+        SimThread {
+            network: self.clone(),
+            task,
+            handle,
+        }
+    }
+
+    // 個別に設定していないリンクの特性
+    pub fn set_default_link(&self, config: LinkConfig) -> Result<()> {
+        config.validate()?;
+        self.lock().default_link = config;
+        Ok(())
+    }
+
+    // from から to への片方向のリンクの特性を設定する
+    pub fn set_link(&self, from: IpAddr, to: IpAddr, config: LinkConfig) -> Result<()> {
+        config.validate()?;
+        self.lock().links.insert((from, to), config);
+        Ok(())
+    }
+
+    // 仮想時計の現在時刻
+    pub fn now(&self) -> Duration {
+        self.lock().now
+    }
+
+    // 仮想時計を進め、その間に届くパケットを配送する
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.lock();
+        state.now += duration;
+        state.deliver_due();
+    }
+
+    // ネットワーク上のホストのアドレスにバインドしたソケットを生成する
+    // ホストはアドレスを指定した時点で存在することになる
+    pub fn bind<T: ToSocketAddrs>(&self, addr: T) -> Result<UdpSocket> {
+        let addr = resolve(addr)?;
+        if addr.ip().is_unspecified() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "simulated sockets must be bound to a host address",
+            )
+            .into());
+        }
+        let backend = SimBackend {
+            network: self.clone(),
+            local: addr,
+            current: Vec::new(),
+            deadline: None,
+        };
+        UdpSocket::with_backend(addr, backend)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.state) as usize
+    }
+
+    // 呼び出したスレッドのタスク
+    fn current_task(&self) -> usize {
+        match TASK.with(Cell::get) {
+            Some((network, task)) if network == self.id() => task,
+            _ => 0,
+        }
+    }
+
+    // 状態を変えたタスクから実行を譲り、自分の番が来るまで待つ
+    fn yield_turn<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, State>,
+        task: usize,
+    ) -> MutexGuard<'a, State> {
+        state.schedule();
+        self.turn.notify_all();
+        self.wait_turn(state, task)
+    }
+
+    fn wait_turn<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, State>,
+        task: usize,
+    ) -> MutexGuard<'a, State> {
+        while state.running != Some(task) {
+            state = self.turn.wait(state).unwrap();
+        }
+        state
+    }
+}
+
+// spawn で起動したスレッド
+pub struct SimThread<T> {
+    network: SimNetwork,
+    task: usize,
+    handle: JoinHandle<T>,
+}
+
+impl<T> SimThread<T> {
+    // スレッドの終了を待つ。待っている間は他のタスクを実行する
+    pub fn join(self) -> thread::Result<T> {
+        let task = self.network.current_task();
+        let mut state = self.network.lock();
+        state.tasks[task] = Task::Joining(self.task);
+        let mut state = self.network.yield_turn(state, task);
+        state.tasks[task] = Task::Ready;
+        drop(state);
+        self.handle.join()
+    }
+}
+
+// タスクの終了を記録し、次のタスクに実行を譲る
+struct Finish<'a> {
+    network: &'a SimNetwork,
+    task: usize,
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        let mut state = match self.network.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.tasks[self.task] = Task::Finished;
+        state.schedule();
+        self.network.turn.notify_all();
+    }
+}
+
+// SimNetwork 上のソケットの送受信
+struct SimBackend {
+    network: SimNetwork,
+    // bind_port で割り当てたポートで確定する
+    local: SocketAddr,
+    // 最後に受信したUDPパケット
+    current: Vec<u8>,
+    // 最後に読み替えた実時間の期限と仮想時計の期限
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl Backend for SimBackend {
+    fn supports(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.local.is_ipv4()
+    }
+
+    fn send(&mut self, packet: &[u8], source: IpAddr, destination: IpAddr) -> io::Result<usize> {
+        // UDPヘッダから宛先ポートを読む
+        if packet.len() < 4 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let port = u16::from_be_bytes([packet[2], packet[3]]);
+        self.network
+            .lock()
+            .transmit(source, SocketAddr::new(destination, port), packet);
+        Ok(packet.len())
+    }
+
+    // 届いていなければ他のタスクに実行を譲る
+    fn recv(&mut self, deadline: Option<Instant>) -> io::Result<Option<Datagram>> {
+        let task = self.network.current_task();
+        let mut state = self.network.lock();
+        // 実時間の期限を仮想時計の期限に読み替える
+        // 呼び出しまでに経過した僅かな実時間で結果が変わらないようにミリ秒単位に切り上げる
+        // 無関係なデータグラムを読み飛ばして同じ期限で呼ばれた場合は、前に読み替えたものを使う
+        let deadline = match (deadline, self.deadline) {
+            (Some(deadline), Some((cached, converted))) if cached == deadline => Some(converted),
+            (Some(deadline), _) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let converted =
+                    state.now + Duration::from_millis(remaining.as_micros().div_ceil(1000) as u64);
+                self.deadline = Some((deadline, converted));
+                Some(converted)
+            }
+            (None, _) => None,
+        };
+        loop {
+            state.deliver_due();
+            let received = state
+                .sockets
+                .get_mut(&self.local)
+                .and_then(|queue| queue.pop_front());
+            if let Some((source, packet)) = received {
+                state.tasks[task] = Task::Ready;
+                self.current = packet;
+                return Ok(Some(Datagram::new(source, self.local.ip())));
+            }
+            if deadline.is_some_and(|deadline| deadline <= state.now) {
+                state.tasks[task] = Task::Ready;
+                return Ok(None);
+            }
+            state.tasks[task] = Task::Receiving(self.local, deadline);
+            state = self.network.yield_turn(state, task);
+        }
+    }
+
+    fn packet(&self) -> &[u8] {
+        &self.current
+    }
+
+    // ポート番号はホスト毎に管理する
+    fn bind_port(&mut self, ip: IpAddr, port: u16) -> io::Result<u16> {
+        let mut state = self.network.lock();
+        let in_use = state
+            .sockets
+            .keys()
+            .filter(|addr| addr.ip() == ip)
+            .map(|addr| addr.port())
+            .collect::<BTreeSet<_>>();
+        let port = match port {
+            0 => (EPHEMERAL_START..=u16::MAX)
+                .find(|port| !in_use.contains(port))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?,
+            port if in_use.contains(&port) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("port {} is already in use", port),
+                ))
+            }
+            port => port,
+        };
+        self.local = SocketAddr::new(ip, port);
+        state.sockets.insert(self.local, VecDeque::new());
+        Ok(port)
+    }
+
+    fn release_port(&mut self, _port: u16) {
+        self.network.lock().sockets.remove(&self.local);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "10.0.0.1:7";
+    const CLIENT: &str = "10.0.0.2:0";
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    // 届いたデータグラムを送信元に返し続ける
+    fn echo_server(network: &SimNetwork) {
+        let mut socket = network.bind(SERVER).unwrap();
+        network.spawn(move || {
+            let mut buffer = [0; 1500];
+            loop {
+                let (size, source) = socket.recv_from(&mut buffer).unwrap();
+                socket.send_to(&buffer[..size], source).unwrap();
+            }
+        });
+    }
+
+    // 要求を count 回送り、それぞれの応答を受信した時刻 (タイムアウトした場合は None) を返す
+    fn exchange(network: &SimNetwork, count: usize) -> Vec<Option<Duration>> {
+        let mut socket = network.bind(CLIENT).unwrap();
+        socket.connect(SERVER).unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut buffer = [0; 1500];
+        (0..count)
+            .map(|i| {
+                let request = format!("request {}", i);
+                socket.send(request.as_bytes()).unwrap();
+                match socket.recv(&mut buffer) {
+                    Ok(size) => {
+                        assert_eq!(&buffer[..size], request.as_bytes());
+                        Some(network.now())
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => None,
+                    Err(e) => panic!("{}", e),
+                }
+            })
+            .collect()
+    }
+
+    fn lossy_exchange(seed: u64, latency: Duration) -> Vec<Option<Duration>> {
+        let network = SimNetwork::new(seed);
+        network
+            .set_default_link(LinkConfig {
+                loss: 0.3,
+                latency,
+                jitter: latency / 2,
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        echo_server(&network);
+        exchange(&network, 20)
+    }
+
+    #[test]
+    fn echo_takes_round_trip_time() {
+        let network = SimNetwork::new(1);
+        network
+            .set_default_link(LinkConfig {
+                latency: Duration::from_millis(10),
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        echo_server(&network);
+        let expected = (1..=5)
+            .map(|i| Some(Duration::from_millis(20 * i)))
+            .collect::<Vec<_>>();
+        assert_eq!(exchange(&network, 5), expected);
+    }
+
+    #[test]
+    fn timeout_advances_virtual_clock() {
+        let network = SimNetwork::new(1);
+        network
+            .set_default_link(LinkConfig {
+                loss: 1.0,
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        echo_server(&network);
+        assert_eq!(exchange(&network, 3), vec![None; 3]);
+        assert_eq!(network.now(), TIMEOUT * 3);
+    }
+
+    #[test]
+    fn same_seed_gives_same_result() {
+        for latency in [Duration::ZERO, Duration::from_millis(10)] {
+            let first = lossy_exchange(42, latency);
+            assert!(first.iter().any(Option::is_some));
+            assert!(first.iter().any(Option::is_none));
+            for _ in 0..5 {
+                assert_eq!(lossy_exchange(42, latency), first);
+            }
+        }
+    }
+
+    #[test]
+    fn discarded_datagrams_do_not_extend_timeout() {
+        let network = SimNetwork::new(1);
+        let mut client = network.bind(CLIENT).unwrap();
+        client.connect(SERVER).unwrap();
+        client.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let target = client.local_addr();
+        // 通信相手以外から 1 秒毎に届くデータグラム
+        let mut other = network.bind("10.0.0.3:7").unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        network.spawn(move || {
+            let mut buffer = [0; 16];
+            for _ in 0..10 {
+                assert!(other.recv_from(&mut buffer).is_err());
+                other.send_to(b"noise", target).unwrap();
+            }
+        });
+        let mut buffer = [0; 16];
+        let error = client.recv(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(network.now(), TIMEOUT);
+        assert_eq!(client.stats().wrong_peer, 2);
+    }
+
+    const RECEIVER: &str = "10.0.0.4:7";
+
+    // 番号を付けたデータグラムを count 個送り、届いた番号と時刻をタイムアウトするまで記録する
+    fn deliveries(network: &SimNetwork, count: u8, size: usize) -> Vec<(u8, Duration)> {
+        let mut receiver = network.bind(RECEIVER).unwrap();
+        receiver.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut sender = network.bind(CLIENT).unwrap();
+        for i in 0..count {
+            sender.send_to(&vec![i; size], RECEIVER).unwrap();
+        }
+        let mut buffer = [0; 1500];
+        let mut received = Vec::new();
+        while let Ok((_, _)) = receiver.recv_from(&mut buffer) {
+            received.push((buffer[0], network.now()));
+        }
+        received
+    }
+
+    #[test]
+    fn duplicates_packets() {
+        let network = SimNetwork::new(1);
+        network
+            .set_default_link(LinkConfig {
+                duplicate: 1.0,
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        let numbers = deliveries(&network, 3, 1)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec![0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let network = SimNetwork::new(1);
+        network
+            .set_default_link(LinkConfig {
+                reorder: 0.5,
+                latency: Duration::from_millis(10),
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        let mut numbers = deliveries(&network, 20, 1)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_ne!(numbers, (0..20).collect::<Vec<_>>());
+        numbers.sort();
+        assert_eq!(numbers, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn jitter_spreads_delivery_times() {
+        let network = SimNetwork::new(1);
+        let latency = Duration::from_millis(10);
+        network
+            .set_default_link(LinkConfig {
+                latency,
+                jitter: latency,
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        let times = deliveries(&network, 20, 1)
+            .into_iter()
+            .map(|(_, time)| time)
+            .collect::<Vec<_>>();
+        assert_eq!(times.len(), 20);
+        assert!(times
+            .iter()
+            .all(|&time| latency <= time && time <= latency * 2));
+        assert!(times.iter().any(|&time| time != times[0]));
+    }
+
+    #[test]
+    fn bandwidth_serializes_packets() {
+        let network = SimNetwork::new(1);
+        network
+            .set_default_link(LinkConfig {
+                bandwidth: Some(1000),
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        // UDPヘッダを含めて 125 バイトなので 1 つ送り出すのに 125 ミリ秒かかる
+        let times = deliveries(&network, 4, 117)
+            .into_iter()
+            .map(|(_, time)| time)
+            .collect::<Vec<_>>();
+        let expected = (1..=4)
+            .map(|i| Duration::from_millis(125 * i))
+            .collect::<Vec<_>>();
+        assert_eq!(times, expected);
+    }
+
+    #[test]
+    fn rejects_zero_bandwidth() {
+        let network = SimNetwork::new(1);
+        let config = LinkConfig {
+            bandwidth: Some(0),
+            ..LinkConfig::default()
+        };
+        assert!(network.set_default_link(config).is_err());
+        let (from, to) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert!(network.set_link(from, to, config).is_err());
+    }
+
+    #[test]
+    fn join_runs_other_tasks() {
+        let network = SimNetwork::new(1);
+        network
+            .set_default_link(LinkConfig {
+                latency: Duration::from_millis(5),
+                ..LinkConfig::default()
+            })
+            .unwrap();
+        echo_server(&network);
+        let client = network.spawn({
+            let network = network.clone();
+            move || exchange(&network, 3)
+        });
+        assert_eq!(client.join().unwrap().len(), 3);
+        assert_eq!(network.now(), Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,34 @@
+// mio のイベントループに UdpSocket を登録する
+// 登録するのは AsRawFd の epoll インスタンスなので、mio の通知はエッジトリガになる
+// 通知を受けたらノンブロッキングモードの recv_from で WouldBlock になるまで受信すること
+use crate::UdpSocket;
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+impl Source for UdpSocket {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.check_pollable()?;
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
@@ -0,0 +1,471 @@
+// 1つの raw チャネルを複数のソケットで共有する
+// 受信スレッドが宛先ポートを見て各ソケットのキューに振り分けるので、
+// ソケットの数だけカーネルがパケットをコピーすることがなくなる
+use crate::backend::{Backend, Datagram};
+use crate::icmp::{self, Kind};
+use crate::pmtu::PmtuCache;
+use crate::raw::{self, Channel};
+use crate::sys;
+use crate::{port, resolve, Error, Result, UdpSocket};
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::transport::{TransportReceiver, TransportSender};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{self, IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// ソケット毎の受信キューの長さ。溢れた分は捨てる
+const QUEUE_SIZE: usize = 1024;
+// ブロードキャストアドレスかどうかを確かめる connect の宛先ポート (connect ではパケットは送信されない)
+const DISCARD_PORT: u16 = 9;
+// 受信スレッドがスタックの破棄を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// キューで受け渡すデータグラム
+struct Received {
+    datagram: Datagram,
+    packet: Vec<u8>,
+}
+
+// データグラムの代わりに ICMP エラーを受け渡すこともある
+type Item = io::Result<Received>;
+
+// ソケットの受信キュー
+struct Queue {
+    sender: SyncSender<Item>,
+    // キューに入れたら読み込み可能にする eventfd
+    ready: Arc<OwnedFd>,
+    // キューが溢れて捨てた数
+    overflow: Arc<AtomicU64>,
+    // connect した通信相手
+    peer: Option<SocketAddr>,
+}
+
+impl Queue {
+    // キューが溢れている場合はカーネルと同様に捨てて数える
+    fn push(&self, item: Item) {
+        match self.sender.try_send(item) {
+            Ok(()) => sys::eventfd_notify(self.ready.as_raw_fd()),
+            Err(TrySendError::Full(_)) => {
+                self.overflow.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+struct Shared {
+    v4: Mutex<TransportSender>,
+    v6: Option<Mutex<TransportSender>>,
+    icmp_v4: Mutex<TransportSender>,
+    icmp_v6: Option<Mutex<TransportSender>>,
+    // 宛先ポート番号毎の受信キュー
+    sockets: Mutex<HashMap<u16, Queue>>,
+    // 共有の ICMP チャネルで知った経路MTU
+    pmtu: Mutex<PmtuCache>,
+    // ブロードキャストを許可しているソケットの数
+    broadcast: Mutex<usize>,
+    // バインドされていないポート宛てのデータグラムに Port Unreachable を返すかどうか
+    port_unreachable: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct UdpStack {
+    shared: Arc<Shared>,
+}
+
+impl UdpStack {
+    // IPv4 と IPv6 の raw チャネルを開き、受信スレッドを起動する
+    // IPv6 が使えないホストでは IPv4 だけで動作する
+    pub fn new() -> Result<Self> {
+        let v4 = Channel::v4()?;
+        let icmp_v4 = Channel::icmp_v4()?;
+        let v6 = Channel::v6()
+            .and_then(|v6| Ok((v6, Channel::icmp_v6()?)))
+            .ok();
+        let mut receivers = vec![v4.receiver];
+        let mut icmp_receivers = vec![icmp_v4.receiver];
+        let (v6, icmp_v6) = match v6 {
+            Some((v6, icmp_v6)) => {
+                receivers.push(v6.receiver);
+                icmp_receivers.push(icmp_v6.receiver);
+                (
+                    Some(Mutex::new(v6.sender)),
+                    Some(Mutex::new(icmp_v6.sender)),
+                )
+            }
+            None => (None, None),
+        };
+        let shared = Arc::new(Shared {
+            v4: Mutex::new(v4.sender),
+            v6,
+            icmp_v4: Mutex::new(icmp_v4.sender),
+            icmp_v6,
+            sockets: Mutex::new(HashMap::new()),
+            pmtu: Mutex::new(PmtuCache::new()),
+            broadcast: Mutex::new(0),
+            port_unreachable: AtomicBool::new(false),
+        });
+        for receiver in receivers {
+            let shared = Arc::downgrade(&shared);
+            thread::spawn(move || demultiplex(receiver, shared));
+        }
+        for (i, receiver) in icmp_receivers.into_iter().enumerate() {
+            let shared = Arc::downgrade(&shared);
+            thread::spawn(move || demultiplex_icmp(receiver, i == 1, shared));
+        }
+        Ok(Self { shared })
+    }
+
+    // バインドされていないポートにデータグラムが届いた時に ICMP Port Unreachable を返す
+    // ホストのカーネルにもそのポートのソケットが無い場合は、カーネルも別に返す
+    pub fn set_port_unreachable(&self, enabled: bool) {
+        self.shared
+            .port_unreachable
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    // スタック上にソケットを生成する
+    pub fn bind<T: ToSocketAddrs>(&self, addr: T) -> Result<UdpSocket> {
+        let addr = resolve(addr)?;
+        let (v4, v6) = match addr.ip() {
+            IpAddr::V4(_) => (true, false),
+            IpAddr::V6(ip) => (ip.is_unspecified(), true),
+        };
+        if v6 && self.shared.v6.is_none() {
+            return Err(Error::UnsupportedFamily(addr));
+        }
+        let port = port::allocate(addr.ip(), addr.port())?;
+        let ready = match sys::eventfd() {
+            Ok(ready) => Arc::new(ready),
+            Err(e) => {
+                port::release(port);
+                return Err(e.into());
+            }
+        };
+        let (sender, queue) = mpsc::sync_channel(QUEUE_SIZE);
+        let overflow = Arc::new(AtomicU64::new(0));
+        self.shared.sockets.lock().unwrap().insert(
+            port,
+            Queue {
+                sender,
+                ready: ready.clone(),
+                overflow: overflow.clone(),
+                peer: None,
+            },
+        );
+        let link = StackLink {
+            shared: self.shared.clone(),
+            port,
+            v4,
+            v6,
+            queue,
+            ready,
+            overflow,
+            backlog: VecDeque::new(),
+            current: None,
+            broadcast: false,
+        };
+        UdpSocket::with_link(addr.ip(), port, Box::new(link))
+    }
+}
+
+impl Shared {
+    // 受信したデータグラムに Port Unreachable を返す
+    // v4 の raw チャネルの受信バッファは IP ヘッダから始まっているので、そのまま引用できる
+    fn reply_port_unreachable(&self, datagram: &Datagram, original: &[u8]) {
+        if !icmp::should_reply(datagram) {
+            return;
+        }
+        let _ = match datagram.source {
+            IpAddr::V4(_) => {
+                let reply = icmp::port_unreachable_v4(original);
+                self.icmp_v4
+                    .lock()
+                    .unwrap()
+                    .send_to(IcmpPacket::new(&reply).unwrap(), datagram.source)
+            }
+            IpAddr::V6(_) => {
+                let reply = icmp::port_unreachable_v6(datagram, original);
+                match &self.icmp_v6 {
+                    Some(sender) => sender
+                        .lock()
+                        .unwrap()
+                        .send_to(Icmpv6Packet::new(&reply).unwrap(), datagram.source),
+                    None => return,
+                }
+            }
+        };
+    }
+}
+
+// 共有チャネルからソケットのキューにデータグラムを振り分ける
+// スタックと全てのソケットが破棄されたら終了する
+fn demultiplex(mut receiver: TransportReceiver, shared: Weak<Shared>) {
+    loop {
+        let readable =
+            sys::poll_readable(&[receiver.socket.fd], Some(Instant::now() + POLL_INTERVAL));
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        if !matches!(readable, Ok(Some(_))) {
+            continue;
+        }
+        let (datagram, range) = match raw::recv(&mut receiver, 0) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let packet = &receiver.buffer[range.clone()];
+        let port = match UdpPacket::new(packet) {
+            Some(udp_packet) => udp_packet.get_destination(),
+            None => continue,
+        };
+        let sockets = shared.sockets.lock().unwrap();
+        match sockets.get(&port) {
+            Some(queue) => queue.push(Ok(Received {
+                datagram,
+                packet: packet.to_vec(),
+            })),
+            None if shared.port_unreachable.load(Ordering::Relaxed) => {
+                drop(sockets);
+                let original = match datagram.source {
+                    IpAddr::V4(_) => &receiver.buffer[..range.end],
+                    IpAddr::V6(_) => packet,
+                };
+                shared.reply_port_unreachable(&datagram, original);
+            }
+            None => {}
+        }
+    }
+}
+
+// 共有の ICMP チャネルで受信したエラーを処理する
+// 経路MTUは全てのソケットで共有し、Port Unreachable は送信元ポートのソケットが connect した相手からのものだけ知らせる
+fn demultiplex_icmp(mut receiver: TransportReceiver, v6: bool, shared: Weak<Shared>) {
+    loop {
+        let readable =
+            sys::poll_readable(&[receiver.socket.fd], Some(Instant::now() + POLL_INTERVAL));
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        if !matches!(readable, Ok(Some(_))) {
+            continue;
+        }
+        let len = match sys::recv(receiver.socket.fd, &mut receiver.buffer, 0) {
+            Ok(len) => len,
+            Err(_) => continue,
+        };
+        let packet = &receiver.buffer[..len];
+        let report = if v6 {
+            icmp::parse_v6(packet)
+        } else {
+            icmp::parse_v4(packet)
+        };
+        let report = match report {
+            Some(report) => report,
+            None => continue,
+        };
+        if let Kind::FragmentationNeeded(mtu) = report.kind {
+            shared
+                .pmtu
+                .lock()
+                .unwrap()
+                .update(report.destination.ip(), mtu);
+            continue;
+        }
+        let sockets = shared.sockets.lock().unwrap();
+        match sockets.get(&report.source.port()) {
+            Some(queue) if report.refuses(report.source.port(), queue.peer) => {
+                queue.push(Err(icmp::refused()))
+            }
+            _ => {}
+        }
+    }
+}
+
+// スタック上のソケットの送受信
+pub(crate) struct StackLink {
+    shared: Arc<Shared>,
+    port: u16,
+    v4: bool,
+    v6: bool,
+    queue: Receiver<Item>,
+    ready: Arc<OwnedFd>,
+    overflow: Arc<AtomicU64>,
+    // 送信前にエラーを探すためキューから取り出したデータグラム
+    backlog: VecDeque<Received>,
+    // 最後に受信したデータグラム
+    current: Option<Received>,
+    broadcast: bool,
+}
+
+impl StackLink {
+    // 受信キューを外す。ポートを解放した後に同じポートでバインドされたソケットのキューは残す
+    fn unregister(&self) {
+        let mut sockets = self.shared.sockets.lock().unwrap();
+        if sockets
+            .get(&self.port)
+            .is_some_and(|queue| Arc::ptr_eq(&queue.ready, &self.ready))
+        {
+            sockets.remove(&self.port);
+        }
+    }
+
+    // キューに届いている ICMP エラーを取り出す。データグラムは backlog に移す
+    fn take_error(&mut self) -> io::Result<()> {
+        let mut error = Ok(());
+        loop {
+            match self.queue.try_recv() {
+                Ok(Ok(received)) => self.backlog.push_back(received),
+                Ok(Err(e)) => error = Err(e),
+                Err(TryRecvError::Empty) => return error,
+                Err(TryRecvError::Disconnected) => return Err(disconnected()),
+            }
+        }
+    }
+}
+
+impl Backend for StackLink {
+    fn supports(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => self.v4,
+            IpAddr::V6(_) => self.v6,
+        }
+    }
+
+    fn set_v4(&mut self, enabled: bool) -> io::Result<()> {
+        self.v4 = enabled;
+        Ok(())
+    }
+
+    // 前の送信に対する ICMP エラーが届いていれば送信せずにエラーを返す
+    fn send(&mut self, packet: &[u8], _source: IpAddr, dest: IpAddr) -> io::Result<usize> {
+        self.take_error()?;
+        let channel = match dest {
+            IpAddr::V4(_) if self.v4 => Some(&self.shared.v4),
+            IpAddr::V6(_) if self.v6 => self.shared.v6.as_ref(),
+            _ => None,
+        };
+        let channel = channel.ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+        if dest.is_ipv4() && !self.broadcast && *self.shared.broadcast.lock().unwrap() > 0 {
+            reject_broadcast(dest)?;
+        }
+        raw::send(&mut channel.lock().unwrap(), packet, dest)
+    }
+
+    // カーネルの経路キャッシュと、共有の ICMP チャネルで知った値の小さい方
+    fn path_mtu(&mut self, destination: IpAddr) -> io::Result<usize> {
+        let mtu = sys::path_mtu(destination)?;
+        Ok(self
+            .shared
+            .pmtu
+            .lock()
+            .unwrap()
+            .get(destination)
+            .map_or(mtu, |pmtu| pmtu.min(mtu)))
+    }
+
+    // 送信チャネルは全てのソケットで共有しているので、許可しているソケットが1つでもあれば SO_BROADCAST を立てる
+    fn set_broadcast(&mut self, enabled: bool) -> io::Result<()> {
+        if enabled == self.broadcast {
+            return Ok(());
+        }
+        let mut count = self.shared.broadcast.lock().unwrap();
+        let next = if enabled { *count + 1 } else { *count - 1 };
+        if (*count == 0) != (next == 0) {
+            let fd = self.shared.v4.lock().unwrap().socket.fd;
+            sys::set_broadcast(fd, next > 0)?;
+        }
+        *count = next;
+        self.broadcast = enabled;
+        Ok(())
+    }
+
+    fn set_peer(&mut self, peer: SocketAddr) -> io::Result<()> {
+        if let Some(queue) = self.shared.sockets.lock().unwrap().get_mut(&self.port) {
+            queue.peer = Some(peer);
+        }
+        Ok(())
+    }
+
+    // キューからデータグラムを1つ取り出す
+    // 期限までに受信できなければ None を返す
+    // backlog が空の時だけ eventfd を戻す (キューを見る前に戻して通知を取りこぼさないようにする)
+    fn recv(&mut self, deadline: Option<Instant>) -> io::Result<Option<Datagram>> {
+        if self.backlog.is_empty() {
+            sys::eventfd_clear(self.ready.as_raw_fd());
+        }
+        let received = match self.backlog.pop_front() {
+            Some(received) => received,
+            None => match deadline {
+                Some(deadline) => {
+                    match self
+                        .queue
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(received) => received?,
+                        Err(RecvTimeoutError::Timeout) => return Ok(None),
+                        Err(RecvTimeoutError::Disconnected) => return Err(disconnected()),
+                    }
+                }
+                None => self.queue.recv().map_err(|_| disconnected())??,
+            },
+        };
+        let datagram = received.datagram;
+        self.current = Some(received);
+        Ok(Some(datagram))
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.ready.as_raw_fd()]
+    }
+
+    // 他のスレッドが同じポートにバインドする前にキューを外す
+    fn release_port(&mut self, port: u16) {
+        self.unregister();
+        port::release(port);
+    }
+
+    fn queue_overflow(&self) -> u64 {
+        self.overflow.load(Ordering::Relaxed)
+    }
+
+    fn packet(&self) -> &[u8] {
+        self.current
+            .as_ref()
+            .map_or(&[], |received| &received.packet[..])
+    }
+}
+
+impl Drop for StackLink {
+    fn drop(&mut self) {
+        self.unregister();
+        let _ = self.set_broadcast(false);
+    }
+}
+
+// 他のソケットが共有の送信チャネルに SO_BROADCAST を立てている間は、カーネルの代わりに拒否する
+// SO_BROADCAST の無いソケットをブロードキャストアドレスに connect すると EACCES になる
+fn reject_broadcast(destination: IpAddr) -> io::Result<()> {
+    let probe = net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    match probe.connect((destination, DISCARD_PORT)) {
+        Err(e) if e.raw_os_error() == Some(libc::EACCES) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "stack receive thread has stopped",
+    )
+}
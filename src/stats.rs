@@ -0,0 +1,45 @@
+// 受信時に破棄したパケットの統計
+
+// パケットを破棄した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    // バインドしたアドレス以外に届いた
+    WrongAddress,
+    // バインドしたポート以外に届いた
+    WrongPort,
+    // connect した通信相手以外から届いた
+    WrongPeer,
+    // チェックサムが一致しない
+    BadChecksum,
+    // IPヘッダやUDPヘッダが壊れている
+    Malformed,
+    // ソケットからの読み込みに失敗した
+    ReadError,
+}
+
+// ソケット毎の破棄したパケットの数
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub wrong_address: u64,
+    pub wrong_port: u64,
+    pub wrong_peer: u64,
+    pub bad_checksum: u64,
+    pub malformed: u64,
+    pub read_errors: u64,
+    // 受信キューが溢れて捨てた数 (UdpStack のソケットだけが数える)
+    pub queue_overflow: u64,
+}
+
+impl Stats {
+    pub(crate) fn record(&mut self, reason: DropReason) {
+        let counter = match reason {
+            DropReason::WrongAddress => &mut self.wrong_address,
+            DropReason::WrongPort => &mut self.wrong_port,
+            DropReason::WrongPeer => &mut self.wrong_peer,
+            DropReason::BadChecksum => &mut self.bad_checksum,
+            DropReason::Malformed => &mut self.malformed,
+            DropReason::ReadError => &mut self.read_errors,
+        };
+        *counter += 1;
+    }
+}
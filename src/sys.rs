@@ -0,0 +1,607 @@
+// pnet が提供していないソケット操作を libc で直接行う
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant, SystemTime};
+
+// 戻り値が負ならOSのエラーに変換する
+fn cvt(ret: libc::ssize_t) -> io::Result<usize> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+fn setsockopt_int(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    cvt(ret as libc::ssize_t).map(|_| ())
+}
+
+fn getsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    cvt(ret as libc::ssize_t).map(|_| value)
+}
+
+// IPv4 のマルチキャストグループに参加する、または離脱する
+// interface は参加するインターフェースのアドレス (0.0.0.0 ならカーネルが経路で選ぶ)
+pub(crate) fn set_membership_v4(
+    fd: RawFd,
+    group: Ipv4Addr,
+    interface: Ipv4Addr,
+    join: bool,
+) -> io::Result<()> {
+    let mreq = libc::ip_mreq {
+        imr_multiaddr: libc::in_addr {
+            s_addr: u32::from(group).to_be(),
+        },
+        imr_interface: libc::in_addr {
+            s_addr: u32::from(interface).to_be(),
+        },
+    };
+    let name = if join {
+        libc::IP_ADD_MEMBERSHIP
+    } else {
+        libc::IP_DROP_MEMBERSHIP
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            name,
+            &mreq as *const libc::ip_mreq as *const libc::c_void,
+            mem::size_of::<libc::ip_mreq>() as libc::socklen_t,
+        )
+    };
+    cvt(ret as libc::ssize_t).map(|_| ())
+}
+
+pub(crate) fn set_multicast_ttl_v4(fd: RawFd, ttl: u8) -> io::Result<()> {
+    setsockopt_int(
+        fd,
+        libc::IPPROTO_IP,
+        libc::IP_MULTICAST_TTL,
+        ttl as libc::c_int,
+    )
+}
+
+pub(crate) fn set_multicast_loop_v4(fd: RawFd, enabled: bool) -> io::Result<()> {
+    setsockopt_int(
+        fd,
+        libc::IPPROTO_IP,
+        libc::IP_MULTICAST_LOOP,
+        enabled as libc::c_int,
+    )
+}
+
+pub(crate) fn set_broadcast(fd: RawFd, enabled: bool) -> io::Result<()> {
+    setsockopt_int(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_BROADCAST,
+        enabled as libc::c_int,
+    )
+}
+
+// マルチキャスト以外を送信するインターフェースを指定する。0 なら経路で選ぶ
+// IPv4 ではインデックスをネットワークバイトオーダーで渡す
+pub(crate) fn set_unicast_if_v4(fd: RawFd, index: u32) -> io::Result<()> {
+    setsockopt_int(
+        fd,
+        libc::IPPROTO_IP,
+        libc::IP_UNICAST_IF,
+        index.to_be() as libc::c_int,
+    )
+}
+
+// マルチキャストを送信するインターフェースをアドレスで指定する
+// カーネルはこのアドレスを送信元にする
+pub(crate) fn set_multicast_if_v4(fd: RawFd, interface: Ipv4Addr) -> io::Result<()> {
+    let addr = libc::in_addr {
+        s_addr: u32::from(interface).to_be(),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &addr as *const libc::in_addr as *const libc::c_void,
+            mem::size_of::<libc::in_addr>() as libc::socklen_t,
+        )
+    };
+    cvt(ret as libc::ssize_t).map(|_| ())
+}
+
+// ソケットに classic BPF のプログラムをアタッチする。既にあれば置き換える
+pub(crate) fn attach_filter(fd: RawFd, program: &[libc::sock_filter]) -> io::Result<()> {
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &fprog as *const libc::sock_fprog as *const libc::c_void,
+            mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    cvt(ret as libc::ssize_t).map(|_| ())
+}
+
+// IPv4 で受信したパケットが到着したインターフェースと受信時刻を補助データで受け取る
+pub(crate) fn set_recv_ancillary_v4(fd: RawFd) -> io::Result<()> {
+    setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
+    setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1)
+}
+
+// IPv6 ではヘッダが渡されないので、宛先アドレスと Hop Limit、Traffic Class も補助データで受け取る
+pub(crate) fn set_recv_ancillary_v6(fd: RawFd) -> io::Result<()> {
+    setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+    setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?;
+    setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+    setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1)
+}
+
+// ユニキャストで送信するパケットの TTL (IPv6 では Hop Limit)
+pub(crate) fn set_ttl(fd: RawFd, v6: bool, ttl: u8) -> io::Result<()> {
+    if v6 {
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            ttl as libc::c_int,
+        )
+    } else {
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+    }
+}
+
+// 送信するパケットの TOS (IPv6 では Traffic Class)
+pub(crate) fn set_tos(fd: RawFd, v6: bool, tos: u8) -> io::Result<()> {
+    if v6 {
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            tos as libc::c_int,
+        )
+    } else {
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int)
+    }
+}
+
+// IPv4 の raw ソケットから IP ヘッダごと受信する
+// flags に MSG_DONTWAIT を指定すると、届いていなければ待たずに WouldBlock を返す
+pub(crate) fn recv(fd: RawFd, buffer: &mut [u8], flags: libc::c_int) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::recv(
+            fd,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+            flags,
+        )
+    };
+    cvt(ret)
+}
+
+// 補助データで受け取ったパケットの情報
+#[derive(Default)]
+pub(crate) struct Ancillary {
+    // IPv6 のみ
+    pub(crate) destination: Option<Ipv6Addr>,
+    pub(crate) hop_limit: Option<u8>,
+    pub(crate) traffic_class: Option<u8>,
+    // 到着したインターフェースのインデックス
+    pub(crate) interface: Option<u32>,
+    // カーネルが受信した時刻
+    pub(crate) timestamp: Option<SystemTime>,
+}
+
+// IPv4 の raw ソケットから IP ヘッダごと受信し、補助データも読む
+pub(crate) fn recv_v4(
+    fd: RawFd,
+    buffer: &mut [u8],
+    flags: libc::c_int,
+) -> io::Result<(usize, Ancillary)> {
+    recvmsg(fd, buffer, flags, None)
+}
+
+// IPv6 の raw ソケットから受信する
+// IPv6 ではヘッダが渡されないため、送信元は sockaddr から、それ以外は補助データから得る
+pub(crate) fn recv_v6(
+    fd: RawFd,
+    buffer: &mut [u8],
+    flags: libc::c_int,
+) -> io::Result<(usize, Ipv6Addr, Ancillary)> {
+    let mut source: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    let (len, ancillary) = recvmsg(fd, buffer, flags, Some(&mut source))?;
+    Ok((len, Ipv6Addr::from(source.sin6_addr.s6_addr), ancillary))
+}
+
+fn recvmsg(
+    fd: RawFd,
+    buffer: &mut [u8],
+    flags: libc::c_int,
+    source: Option<&mut libc::sockaddr_in6>,
+) -> io::Result<(usize, Ancillary)> {
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    // cmsghdr のアラインメントを満たすため u64 の配列を使う
+    let mut control = [0u64; 32];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    if let Some(source) = source {
+        msg.msg_name = source as *mut libc::sockaddr_in6 as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    }
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let len = cvt(unsafe { libc::recvmsg(fd, &mut msg, flags) })?;
+    Ok((len, parse_ancillary(&msg)))
+}
+
+// 既に届いているデータグラムを buffers の数まで recvmmsg でまとめて受信する (待たない)
+// データグラム毎に受信した長さと補助データを返す。IPv6 では送信元も返す
+pub(crate) fn recvmmsg(
+    fd: RawFd,
+    buffers: &mut [Vec<u8>],
+    v6: bool,
+) -> io::Result<Vec<(usize, Option<Ipv6Addr>, Ancillary)>> {
+    let mut sources: Vec<libc::sockaddr_in6> = vec![unsafe { mem::zeroed() }; buffers.len()];
+    let mut iovs = buffers
+        .iter_mut()
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut controls = vec![[0u64; 32]; buffers.len()];
+    let mut msgs = iovs
+        .iter_mut()
+        .zip(controls.iter_mut())
+        .zip(sources.iter_mut())
+        .map(|((iov, control), source)| {
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            if v6 {
+                msg.msg_name = source as *mut libc::sockaddr_in6 as *mut libc::c_void;
+                msg.msg_namelen = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            }
+            msg.msg_iov = iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = mem::size_of_val(control) as _;
+            libc::mmsghdr {
+                msg_hdr: msg,
+                msg_len: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+    let ret = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    let count = cvt(ret as libc::ssize_t)?;
+    Ok(msgs[..count]
+        .iter()
+        .zip(&sources)
+        .map(|(msg, source)| {
+            let source = Some(Ipv6Addr::from(source.sin6_addr.s6_addr)).filter(|_| v6);
+            (msg.msg_len as usize, source, parse_ancillary(&msg.msg_hdr))
+        })
+        .collect())
+}
+
+// 受信したメッセージの補助データを読む
+fn parse_ancillary(msg: &libc::msghdr) -> Ancillary {
+    let mut ancillary = Ancillary::default();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = (data as *const libc::in_pktinfo).read_unaligned();
+                    ancillary.interface = Some(info.ipi_ifindex as u32);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = (data as *const libc::in6_pktinfo).read_unaligned();
+                    ancillary.destination = Some(Ipv6Addr::from(info.ipi6_addr.s6_addr));
+                    ancillary.interface = Some(info.ipi6_ifindex);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                    ancillary.hop_limit = Some((data as *const libc::c_int).read_unaligned() as u8);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    ancillary.traffic_class =
+                        Some((data as *const libc::c_int).read_unaligned() as u8);
+                }
+                (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                    let time = (data as *const libc::timespec).read_unaligned();
+                    ancillary.timestamp = Some(
+                        SystemTime::UNIX_EPOCH
+                            + Duration::new(time.tv_sec as u64, time.tv_nsec as u32),
+                    );
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    ancillary
+}
+
+// 指定したディスクリプタのどれかが読み込み可能になるまで待ち、そのインデックスを返す
+// 期限までに読み込み可能にならなければ None を返す
+pub(crate) fn poll_readable(fds: &[RawFd], deadline: Option<Instant>) -> io::Result<Option<usize>> {
+    let mut pollfds = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    loop {
+        let timeout = match deadline {
+            // 切り上げてミリ秒にする (0 にすると期限前に何度も起きてしまう)
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let millis = remaining.as_micros().div_ceil(1000);
+                millis.min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+        let ret =
+            unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+        match cvt(ret as libc::ssize_t) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        if let Some(i) = pollfds.iter().position(|p| p.revents != 0) {
+            return Ok(Some(i));
+        }
+        if timeout == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+// TUN デバイスを開く。name が空の場合はカーネルが名前を決める
+// IFF_NO_PI を指定するので読み書きするのは IP パケットそのものになる
+pub(crate) fn tun_open(name: &str) -> io::Result<(File, String)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    if name.len() >= ifr.ifr_name.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut ifr) };
+    cvt(ret as libc::ssize_t)?;
+    let name = ifr
+        .ifr_name
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8 as char)
+        .collect();
+    Ok((file, name))
+}
+
+// インターフェースのインデックス。無ければ None
+pub(crate) fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+// インターフェースの MTU を sysfs から読む
+pub(crate) fn interface_mtu(name: &str) -> io::Result<usize> {
+    let mtu = fs::read_to_string(format!("/sys/class/net/{}/mtu", name))?;
+    mtu.trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid MTU"))
+}
+
+// 送信するパケットの断片化を禁止する (IPv4 では DF ビットを立てる)
+// 禁止しない場合は経路MTUを超えるパケットをカーネルが断片化する
+pub(crate) fn set_dont_fragment(fd: RawFd, v6: bool, enabled: bool) -> io::Result<()> {
+    if v6 {
+        let value = if enabled {
+            libc::IPV6_PMTUDISC_DO
+        } else {
+            libc::IPV6_PMTUDISC_DONT
+        };
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value)
+    } else {
+        let value = if enabled {
+            libc::IP_PMTUDISC_DO
+        } else {
+            libc::IP_PMTUDISC_DONT
+        };
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value)
+    }
+}
+
+// カーネルの経路キャッシュにある宛先までの経路MTU
+// connect したUDPソケットでしか取得できないので、一時的なソケットを使う
+pub(crate) fn path_mtu(destination: IpAddr) -> io::Result<usize> {
+    let (socket, level, name) = match destination {
+        IpAddr::V4(_) => (
+            UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+            libc::IPPROTO_IP,
+            libc::IP_MTU,
+        ),
+        IpAddr::V6(_) => (
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU,
+        ),
+    };
+    // connect ではパケットは送信されない
+    socket.connect((destination, 9))?;
+    getsockopt_int(socket.as_raw_fd(), level, name).map(|mtu| mtu as usize)
+}
+
+// 全てのプロトコルの Ethernet フレームを受信する AF_PACKET ソケット
+// datalink チャネルに渡すと、チャネルの破棄時に閉じられる
+pub(crate) fn packet_socket() -> io::Result<RawFd> {
+    let protocol = (libc::ETH_P_ALL as u16).to_be() as libc::c_int;
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            protocol,
+        )
+    };
+    cvt(fd as libc::ssize_t)?;
+    Ok(fd)
+}
+
+// 他のスレッドからデータが届いたことを知らせるための eventfd
+// 読み込み可能かどうかだけを使うので、書き込まれた値には意味が無い
+pub(crate) fn eventfd() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    cvt(fd as libc::ssize_t)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// eventfd を読み込み可能にする
+pub(crate) fn eventfd_notify(fd: RawFd) {
+    let value: u64 = 1;
+    unsafe {
+        libc::write(fd, &value as *const u64 as *const libc::c_void, 8);
+    }
+}
+
+// eventfd を読み込み可能でない状態に戻す
+pub(crate) fn eventfd_clear(fd: RawFd) {
+    let mut value: u64 = 0;
+    unsafe {
+        libc::read(fd, &mut value as *mut u64 as *mut libc::c_void, 8);
+    }
+}
+
+// 複数のディスクリプタをまとめて待つための epoll インスタンスを作る
+// どれかが読み込み可能になると、返したディスクリプタも読み込み可能になる
+pub(crate) fn epoll(fds: &[RawFd]) -> io::Result<OwnedFd> {
+    let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    cvt(epoll as libc::ssize_t)?;
+    let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+    for &fd in fds {
+        epoll_add(epoll.as_raw_fd(), fd)?;
+    }
+    Ok(epoll)
+}
+
+// epoll インスタンスにディスクリプタを加える。既に加えてあれば何もしない
+// 閉じたディスクリプタはカーネルが取り除く
+pub(crate) fn epoll_add(epoll: RawFd, fd: RawFd) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: fd as u64,
+    };
+    let ret = unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, fd, &mut event) };
+    match cvt(ret as libc::ssize_t) {
+        Err(e) if e.raw_os_error() != Some(libc::EEXIST) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// 宛先アドレスの sockaddr (raw ソケットなのでポート番号は 0)
+fn sockaddr(ip: IpAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match ip {
+        IpAddr::V4(ip) => {
+            let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_addr.s_addr = u32::from(ip).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        IpAddr::V6(ip) => {
+            let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            addr.sin6_addr.s6_addr = ip.octets();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+// 複数のパケットを1回のシステムコールで送り、送信できた数を返す
+// 一度に送れる数には上限 (UIO_MAXIOV) があるので、全て送れるとは限らない
+pub(crate) fn sendmmsg(fd: RawFd, packets: &[(&[u8], IpAddr)]) -> io::Result<usize> {
+    let mut addrs = packets
+        .iter()
+        .map(|&(_, ip)| sockaddr(ip))
+        .collect::<Vec<_>>();
+    let mut iovs = packets
+        .iter()
+        .map(|&(packet, _)| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut msgs = addrs
+        .iter_mut()
+        .zip(iovs.iter_mut())
+        .map(|((addr, len), iov)| {
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_namelen = *len;
+            msg.msg_iov = iov;
+            msg.msg_iovlen = 1;
+            libc::mmsghdr {
+                msg_hdr: msg,
+                msg_len: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+    let ret = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0) };
+    cvt(ret as libc::ssize_t)
+}
@@ -0,0 +1,44 @@
+// IP ヘッダの TOS (IPv6 では Traffic Class) のフィールド
+// 上位 6 ビットが DSCP (RFC 2474)、下位 2 ビットが ECN (RFC 3168)
+const ECN_MASK: u8 = 0b11;
+
+// ECN のコードポイント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecn {
+    // ECN に対応していない
+    NotEct,
+    // ECN に対応している (ECT(1) と ECT(0))
+    Ect1,
+    Ect0,
+    // 経路で輻輳が起きた
+    Ce,
+}
+
+impl Ecn {
+    pub fn from_tos(tos: u8) -> Self {
+        match tos & ECN_MASK {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+pub(crate) fn dscp(tos: u8) -> u8 {
+    tos >> 2
+}
+
+// DSCP と ECN から TOS を組み立てる。dscp は 6 ビットに収まっている必要がある
+pub(crate) fn tos(dscp: u8, ecn: Ecn) -> u8 {
+    dscp << 2 | ecn.bits()
+}
@@ -0,0 +1,293 @@
+// Linux の TUN デバイスを使うバックエンド
+// IPv4 ヘッダもユーザ空間で組み立てるので、ホストのカーネルのUDPを経由せずに
+// 隔離したインターフェース上でこのクレートのUDPを動かせる
+use crate::backend::{Backend, Datagram};
+use crate::fragment::{self, Reassembler};
+use crate::icmp::{self, Listener};
+use crate::igmp::{self, Memberships};
+use crate::ipv4::{self, Header};
+use crate::port;
+use crate::sys;
+use crate::Result;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::time::{Instant, SystemTime};
+
+const BUFFER_SIZE: usize = 65535;
+
+pub struct TunBackend {
+    file: File,
+    name: String,
+    // インターフェースのインデックス
+    index: Option<u32>,
+    // 0.0.0.0 にバインドしたソケットの送信元アドレス
+    local_ip: Option<Ipv4Addr>,
+    mtu: usize,
+    // 受信した ICMP エラー
+    errors: Listener,
+    dont_fragment: bool,
+    // ユニキャストで送信するパケットの TTL と、送信するパケットの TOS
+    ttl: u8,
+    tos: u8,
+    // IPv4 ヘッダの識別子
+    next_id: u16,
+    send_buffer: Vec<u8>,
+    recv_buffer: Vec<u8>,
+    reassembler: Reassembler,
+    multicast: Memberships,
+    // 自分に配送するマルチキャスト (デバイスから読んだものより先に受信する)
+    looped: VecDeque<Vec<u8>>,
+    // looped に入れたら読み込み可能にする eventfd
+    ready: OwnedFd,
+    // 最後に再構築したパケット
+    reassembled: Vec<u8>,
+    // 最後に受信したUDPパケットの範囲と、それが再構築したパケットの中にあるかどうか
+    last: (Range<usize>, bool),
+}
+
+impl TunBackend {
+    // TUN デバイスを開く。アドレスの設定やリンクアップは別途 ip コマンドなどで行う
+    pub fn open(name: &str) -> Result<Self> {
+        let (file, name) = sys::tun_open(name)?;
+        let mtu = sys::interface_mtu(&name).unwrap_or(fragment::DEFAULT_MTU);
+        Ok(Self {
+            file,
+            index: sys::interface_index(&name),
+            local_ip: None,
+            name,
+            mtu,
+            errors: Listener::new(),
+            dont_fragment: false,
+            ttl: ipv4::DEFAULT_TTL,
+            tos: 0,
+            next_id: 0,
+            send_buffer: Vec::new(),
+            recv_buffer: vec![0; BUFFER_SIZE],
+            reassembler: Reassembler::new(),
+            multicast: Memberships::new(),
+            looped: VecDeque::new(),
+            ready: sys::eventfd()?,
+            reassembled: Vec::new(),
+            last: (0..0, false),
+        })
+    }
+
+    // カーネルが割り当てたものを含むインターフェース名
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // 送信するパケットを断片化する大きさ。既定値はデバイスを開いた時の MTU
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+
+    // デバイスの先 (ユーザ空間のスタック) のアドレス
+    // 0.0.0.0 にバインドしたソケットはこのアドレスから送信する
+    pub fn set_local_ip(&mut self, ip: Ipv4Addr) {
+        self.local_ip = Some(ip);
+    }
+
+    // デバイスの MTU と ICMP で知った経路MTUの小さい方
+    fn mtu_for(&mut self, destination: Ipv4Addr) -> usize {
+        self.errors
+            .pmtu
+            .get(IpAddr::V4(destination))
+            .map_or(self.mtu, |mtu| mtu.min(self.mtu))
+    }
+
+    fn send_igmp(&mut self, message: Option<igmp::Message>) -> io::Result<()> {
+        if let Some((_, packet)) = message {
+            self.file.write_all(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for TunBackend {
+    fn supports(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4()
+    }
+
+    // ホストのアドレスから送るとカーネルに捨てられるので、経路表では選ばない
+    fn source_for(&self, _destination: IpAddr) -> io::Result<Option<IpAddr>> {
+        match self.local_ip {
+            Some(ip) => Ok(Some(IpAddr::V4(ip))),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "bind to an address or call TunBackend::set_local_ip",
+            )),
+        }
+    }
+
+    fn send(&mut self, packet: &[u8], source: IpAddr, destination: IpAddr) -> io::Result<usize> {
+        self.errors.take_error()?;
+        let (source, destination) = match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => (source, destination),
+            _ => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+        let header = Header {
+            source,
+            destination,
+            id: self.next_id,
+            ttl: if destination.is_multicast() {
+                self.multicast.ttl
+            } else {
+                self.ttl
+            },
+            tos: self.tos,
+            dont_fragment: self.dont_fragment,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+        let mtu = self.mtu_for(destination);
+        let loopback = self.multicast.loopback && self.multicast.is_member(destination);
+        let file = &mut self.file;
+        let looped = &mut self.looped;
+        let ready = self.ready.as_raw_fd();
+        fragment::fragment(&mut self.send_buffer, &header, packet, mtu, |fragment| {
+            file.write_all(fragment)?;
+            if loopback {
+                looped.push_back(fragment.to_vec());
+                sys::eventfd_notify(ready);
+            }
+            Ok(())
+        })?;
+        Ok(packet.len())
+    }
+
+    fn set_dont_fragment(&mut self, enabled: bool) -> io::Result<()> {
+        self.dont_fragment = enabled;
+        Ok(())
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.tos = tos;
+        Ok(())
+    }
+
+    // パケットはホストのカーネルに渡すだけなので、255.255.255.255 を UdpSocket で検査するだけでよい
+    fn set_broadcast(&mut self, _enabled: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Report の送信元には interface を使う (0.0.0.0 でもよい)
+    fn join_multicast_v4(&mut self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        let report = self.multicast.join(group, interface)?;
+        self.send_igmp(report)
+    }
+
+    fn leave_multicast_v4(&mut self, group: Ipv4Addr, _interface: Ipv4Addr) -> io::Result<()> {
+        let leave = self.multicast.leave(group)?;
+        self.send_igmp(leave)
+    }
+
+    fn set_multicast_ttl_v4(&mut self, ttl: u8) -> io::Result<()> {
+        self.multicast.ttl = ttl;
+        Ok(())
+    }
+
+    fn set_multicast_loop_v4(&mut self, enabled: bool) -> io::Result<()> {
+        self.multicast.loopback = enabled;
+        Ok(())
+    }
+
+    fn path_mtu(&mut self, destination: IpAddr) -> io::Result<usize> {
+        match destination {
+            IpAddr::V4(destination) => Ok(self.mtu_for(destination)),
+            IpAddr::V6(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    // UDP 以外のパケットは読み飛ばし、断片化されたパケットは揃うまで待つ
+    // ICMP エラーは経路MTUの更新と Port Unreachable の通知に使い、IGMP の Query には応答する
+    // マルチキャストは参加しているグループ宛てのものだけ受信する
+    fn recv(&mut self, deadline: Option<Instant>) -> io::Result<Option<Datagram>> {
+        loop {
+            let len = match self.looped.pop_front() {
+                Some(packet) => {
+                    if self.looped.is_empty() {
+                        sys::eventfd_clear(self.ready.as_raw_fd());
+                    }
+                    self.recv_buffer[..packet.len()].copy_from_slice(&packet);
+                    packet.len()
+                }
+                None => {
+                    if sys::poll_readable(&[self.file.as_raw_fd()], deadline)?.is_none() {
+                        return Ok(None);
+                    }
+                    self.file.read(&mut self.recv_buffer)?
+                }
+            };
+            let received = &self.recv_buffer[..len];
+            let reassembled =
+                if ipv4::parse(received)?.is_some_and(|packet| ipv4::is_fragment(&packet)) {
+                    match self.reassembler.reassemble(received)? {
+                        Some(reassembled) => self.reassembled = reassembled,
+                        None => continue,
+                    }
+                    true
+                } else {
+                    false
+                };
+            let packet = if reassembled {
+                &self.reassembled[..]
+            } else {
+                &self.recv_buffer[..len]
+            };
+            if let Some((mut datagram, range)) = ipv4::decapsulate(packet)? {
+                if !self.multicast.accepts(datagram.destination) {
+                    continue;
+                }
+                // デバイスから読んだ時刻を受信時刻にする
+                datagram.interface = self.index;
+                datagram.timestamp = Some(SystemTime::now());
+                self.last = (range, reassembled);
+                return Ok(Some(datagram));
+            }
+            if let Some(report) = icmp::parse_v4(packet) {
+                self.errors.handle(&report);
+                self.errors.take_error()?;
+            }
+            for report in self.multicast.answer(packet) {
+                self.send_igmp(Some(report))?;
+            }
+        }
+    }
+
+    fn bind_port(&mut self, ip: IpAddr, port: u16) -> io::Result<u16> {
+        let port = port::allocate(ip, port)?;
+        self.errors.set_port(port);
+        Ok(port)
+    }
+
+    fn set_peer(&mut self, peer: SocketAddr) -> io::Result<()> {
+        self.errors.set_peer(peer);
+        Ok(())
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.file.as_raw_fd(), self.ready.as_raw_fd()]
+    }
+
+    fn packet(&self) -> &[u8] {
+        let (range, reassembled) = &self.last;
+        if *reassembled {
+            &self.reassembled[range.clone()]
+        } else {
+            &self.recv_buffer[range.clone()]
+        }
+    }
+}